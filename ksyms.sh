@@ -0,0 +1,35 @@
+#!/bin/sh
+
+# 把nm的输出变成内核的符号表(.ksyms节), 格式见src/backtrace.rs
+# 用法: riscv64-unknown-elf-nm -n -C --defined-only os.elf | sh ksyms.sh > os.elf.ksyms.S
+# 只要代码段的符号(t/T), 去掉Rust名字末尾的::h<hash>
+
+awk '
+BEGIN { n = 0 }
+$2 == "t" || $2 == "T" {
+	name = $0
+	sub(/^[0-9a-fA-F]+ [^ ]+ /, "", name)
+	if (name ~ /^\.L/ || name ~ /^\$/)
+		next
+	sub(/::h[0-9a-f]+$/, "", name)
+	addr[n] = $1
+	len[n] = length(name)
+	gsub(/\\/, "\\\\", name)
+	gsub(/"/, "\\\"", name)
+	sym[n] = name
+	n++
+}
+END {
+	print "# 由ksyms.sh生成, 不要手改"
+	print "\t.section .ksyms, \"a\""
+	print "\t.balign 8"
+	printf "\t.dword %d\n", n
+	off = 0
+	for (i = 0; i < n; i++) {
+		printf "\t.dword 0x%s\n\t.4byte %d, %d\n", addr[i], off, len[i]
+		off += len[i]
+	}
+	for (i = 0; i < n; i++)
+		printf "\t.ascii \"%s\"\n", sym[i]
+}
+'
@@ -0,0 +1,85 @@
+// asid.rs
+// ASID(地址空间标识符)分配器
+//
+// 进程里保存的asid上下文 = 代号(generation) | 硬件ASID(低16位)
+// 代号对得上就直接复用, 对不上就重新分一个; 硬件ASID用完了代号加一,
+// 所有hart各自做一次全局TLB刷新后从头再分, 所以切换进程时不用再刷整个TLB
+
+use crate::cpu::{build_satp, satp_asid, satp_fence_all, satp_read, satp_write, MAX_HARTS};
+use crate::lock::Mutex;
+use crate::page::{dealloc, probe_table, satp_mode};
+
+const ASID_MASK: usize = 0xffff;
+const GENERATION_STEP: usize = ASID_MASK + 1;
+
+//hart实现的ASID位数, 0表示不支持ASID
+static mut ASID_BITS: usize = 0;
+//代号从1开始, 0代表进程还没有分到ASID
+static mut GENERATION: usize = GENERATION_STEP;
+//ASID 0留给内核
+static mut NEXT_ASID: usize = 1;
+//代号翻转后每个hart要先刷一次TLB
+static mut FLUSH_PENDING: [bool; MAX_HARTS] = [false; MAX_HARTS];
+static mut ASID_LOCK: Mutex = Mutex::new();
+
+//satp的ASID字段是WARL: 写全1再读回来, 留下的1就是实现了的位
+//用page::probe_table(), S态下写satp马上生效也没关系; 必须在page::set_mode()之后调用
+pub fn init() {
+	let table = probe_table(satp_mode());
+	let old = satp_read();
+	satp_write(build_satp(satp_mode(), ASID_MASK, table as usize));
+	let asid = satp_asid(satp_read());
+	satp_write(old);
+	satp_fence_all();
+	dealloc(table);
+
+	unsafe {
+		ASID_BITS = (asid + 1).trailing_zeros() as usize;
+		println!("ASID bits: {}", ASID_BITS);
+	}
+}
+
+pub fn asid_bits() -> usize {
+	unsafe { ASID_BITS }
+}
+
+fn max_asid() -> usize {
+	(1 << asid_bits()) - 1
+}
+
+//切换到某个进程之前调用, ctx是进程保存的asid上下文
+//返回写进satp的硬件ASID, 需要的话顺便刷新本hart的TLB
+pub fn activate(ctx: &mut usize, hart: usize) -> usize {
+	unsafe {
+		ASID_LOCK.spin_lock();
+
+		if asid_bits() == 0 {
+			//不支持ASID, 每次切换都只能全部刷掉
+			ASID_LOCK.unlock();
+			satp_fence_all();
+			return 0;
+		}
+
+		if *ctx & !ASID_MASK != GENERATION {
+			if NEXT_ASID > max_asid() {
+				//用完了, 开始新的一代, 旧代的ASID全部作废
+				GENERATION += GENERATION_STEP;
+				NEXT_ASID = 1;
+				for pending in FLUSH_PENDING.iter_mut() {
+					*pending = true;
+				}
+			}
+			*ctx = GENERATION | NEXT_ASID;
+			NEXT_ASID += 1;
+		}
+
+		let flush = FLUSH_PENDING[hart];
+		FLUSH_PENDING[hart] = false;
+		ASID_LOCK.unlock();
+
+		if flush {
+			satp_fence_all();
+		}
+		*ctx & ASID_MASK
+	}
+}
@@ -0,0 +1,174 @@
+// backtrace.rs
+// 按帧指针回溯调用栈, 返回地址用链接时嵌进内核的符号表(.ksyms)翻译成函数名
+//
+// 编译时打开了-Cforce-frame-pointers(见.cargo/config): 函数进来后fp(s0) = 进来时的sp, [fp-8] = ra, [fp-16] = 上一层的fp;
+// 汇编写的函数和预编译的core/alloc不一定维护fp, 碰到不合理的fp就停.
+// 符号表由Makefile生成: 第一次链接后用nm导出代码段的符号(ksyms.sh), 再链接一次;
+// .ksyms放在.rodata的最后, 不影响.text里的地址
+
+use core::ptr::null;
+use crate::cpu::{gp, satp_root, CpuMode, Registers, TrapFrame};
+use crate::csr::Sstatus;
+use crate::page::{kernel_root, lookup, EntryBits, Table};
+
+const MAX_DEPTH: usize = 32;
+
+//符号表的一项, 和ksyms.sh的输出一致; 按地址排好序
+#[repr(C)]
+struct Ksym {
+	addr: usize,
+	name: u32, //名字在名字区里的偏移
+	len:  u32,
+}
+
+extern "C" {
+	static KSYMS_START: usize;
+	static KSYMS_END: usize;
+	static TEXT_START: usize;
+	static TEXT_END: usize;
+	static KERNEL_STACK_START: usize;
+	static KERNEL_STACK_END: usize;
+	static HEAP_START: usize;
+	static HEAP_SIZE: usize;
+}
+
+//.ksyms: 符号个数, 然后是Ksym数组和名字区; 第一次链接时是空的
+unsafe fn table() -> (&'static [Ksym], *const u8) {
+	if KSYMS_END - KSYMS_START < 8 {
+		return (&[], null());
+	}
+	let count = *(KSYMS_START as *const usize);
+	let syms = (KSYMS_START + 8) as *const Ksym;
+	(core::slice::from_raw_parts(syms, count), syms.add(count) as *const u8)
+}
+
+//pc所在的函数名和pc在函数里的偏移
+pub fn resolve(pc: usize) -> Option<(&'static str, usize)> {
+	unsafe {
+		if pc < TEXT_START || pc >= TEXT_END {
+			return None;
+		}
+		let (syms, names) = table();
+		//最后一个addr <= pc的符号
+		let i = match syms.binary_search_by(|s| s.addr.cmp(&pc)) {
+			Ok(i) => i,
+			Err(0) => return None,
+			Err(i) => i - 1,
+		};
+		let s = &syms[i];
+		let name = core::slice::from_raw_parts(names.add(s.name as usize), s.len as usize);
+		Some((core::str::from_utf8_unchecked(name), pc - s.addr))
+	}
+}
+
+//回溯的栈在哪: 内核栈直接读, 进程的栈按它的页表翻译, 只读用户页
+enum Stack {
+	Kernel,
+	User(*const Table),
+}
+
+fn read(stack: &Stack, addr: usize) -> Option<usize> {
+	if addr % 8 != 0 {
+		return None;
+	}
+	unsafe {
+		match *stack {
+			//启动栈或者trap栈(从堆里分的页)
+			Stack::Kernel => {
+				let in_stack = addr >= KERNEL_STACK_START && addr < KERNEL_STACK_END;
+				let in_heap = addr >= HEAP_START && addr < HEAP_START + HEAP_SIZE;
+				if !in_stack && !in_heap {
+					return None;
+				}
+				//trap栈下面的保护页在堆里但没有映射, 先查内核页表; 还没建页表时是物理地址, 直接读
+				let root = kernel_root();
+				if !root.is_null() {
+					let m = lookup(&*root, addr)?;
+					if m.flags & EntryBits::Read.val() == 0 {
+						return None;
+					}
+				}
+				Some((addr as *const usize).read_volatile())
+			},
+			Stack::User(root) => {
+				let m = lookup(&*root, addr)?;
+				if m.flags & EntryBits::User.val() == 0 {
+					return None;
+				}
+				Some(((m.paddr | (addr & (m.size() - 1))) as *const usize).read_volatile())
+			},
+		}
+	}
+}
+
+//adjust: pc是返回地址时为1, 落回call指令里, 免得调用noreturn函数的call算到下一个函数头上
+fn print_frame(depth: usize, pc: usize, adjust: usize) {
+	match resolve(pc.wrapping_sub(adjust)) {
+		Some((name, off)) => println!("  #{:<2} {:#x} {}+{:#x}", depth, pc, name, off + adjust),
+		None => println!("  #{:<2} {:#x} ???", depth, pc),
+	}
+}
+
+fn walk(stack: Stack, mut fp: usize, first: usize) {
+	for depth in first..MAX_DEPTH {
+		let ra = match read(&stack, fp.wrapping_sub(8)) {
+			Some(ra) if ra != 0 => ra,
+			_ => return,
+		};
+		print_frame(depth, ra, 1);
+		match read(&stack, fp.wrapping_sub(16)) {
+			//栈往低地址长, 调用者的帧在更高的地址
+			Some(prev) if prev > fp => fp = prev,
+			_ => return,
+		}
+	}
+	println!("  ...");
+}
+
+//沿fp链往上找第一个函数名不以prefixes里任何一个开头的返回地址, 用来跳过分配器这类包装;
+//没有符号表(第一次链接)或者fp链断了时返回None
+#[inline(never)]
+pub fn caller_outside(prefixes: &[&str]) -> Option<usize> {
+	let mut fp: usize;
+	unsafe {
+		llvm_asm!("mv $0, s0" :"=r"(fp));
+	}
+	let stack = Stack::Kernel;
+	for _ in 0..MAX_DEPTH {
+		let ra = read(&stack, fp.wrapping_sub(8))?;
+		let (name, _) = resolve(ra.wrapping_sub(1))?;
+		if !prefixes.iter().any(|p| name.starts_with(p)) {
+			return Some(ra);
+		}
+		match read(&stack, fp.wrapping_sub(16)) {
+			Some(prev) if prev > fp => fp = prev,
+			_ => return None,
+		}
+	}
+	None
+}
+
+//打印本hart从调用者开始的调用栈, panic时用
+#[inline(never)]
+pub fn print_backtrace() {
+	let fp: usize;
+	unsafe {
+		llvm_asm!("mv $0, s0" :"=r"(fp));
+	}
+	println!("Backtrace:");
+	walk(Stack::Kernel, fp, 0);
+}
+
+//打印进trap之前的调用栈: #0是出错的指令epc, 之后从TrapFrame里的s0开始
+pub fn print_trap_backtrace(frame: *const TrapFrame, epc: usize, status: usize) {
+	unsafe {
+		let stack = if Sstatus(status).spp() == CpuMode::User {
+			Stack::User(satp_root((*frame).satp) as *const Table)
+		}else{
+			Stack::Kernel
+		};
+		println!("Backtrace (PID:{}, trapped):", (*frame).pid);
+		print_frame(0, epc, 0);
+		walk(stack, (*frame).regs[gp(Registers::S0)], 1);
+	}
+}
@@ -1,4 +1,5 @@
 use core::ptr::null_mut;
+use crate::csr::{self, Mstatus};
 
 // The frequency of QEMU is 10 MHz
 pub const FREQ: u64 = 10_000_000;
@@ -7,6 +8,7 @@ pub const FREQ: u64 = 10_000_000;
 pub const CONTEXT_SWITCH_TIME: u64 = FREQ;
 
 #[repr(usize)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum SatpMode {
 	Off = 0,
 	Sv39 = 8,
@@ -14,6 +16,7 @@ pub enum SatpMode {
 }
 
 #[repr(usize)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum CpuMode {
 	User = 0,
 	Supervisor = 1,
@@ -112,6 +115,8 @@ pub struct TrapFrame {
 	pub pid:    usize, // 544
 	pub mode:   usize, // 552
     pub trap_stack: *mut u8, //560
+	pub fcsr:   usize, // 568
+	pub fs:     usize, // 576, 进程运行时的mstatus.FS, 见fpu.rs
 }
 
 impl TrapFrame {
@@ -126,11 +131,16 @@ impl TrapFrame {
 			pid:   0,
 			mode:  0,
             trap_stack: null_mut(),
+			fcsr:  0,
+			fs:    0,
 		}
 	}
 }
 
-pub static mut KERNEL_TRAP_FRAME: [TrapFrame; 8] = [TrapFrame::zero(); 8];
+//最多支持的hart个数
+pub const MAX_HARTS: usize = 8;
+
+pub static mut KERNEL_TRAP_FRAME: [TrapFrame; MAX_HARTS] = [TrapFrame::zero(); MAX_HARTS];
 
 //Sv39 mode = 8
 pub const fn build_satp(mode: SatpMode, asid: usize, addr: usize) -> usize {
@@ -139,112 +149,106 @@ pub const fn build_satp(mode: SatpMode, asid: usize, addr: usize) -> usize {
 	| (addr >> 12) & 0xff_ffff_ffff // 保留清了低12位的PPN, 40位？
 }
 
-pub fn mhartid_read() -> usize {
+//从satp中取出根页表的物理地址和ASID
+pub const fn satp_root(satp: usize) -> usize {
+	(satp & 0xfff_ffff_ffff) << 12
+}
+
+pub const fn satp_asid(satp: usize) -> usize {
+	(satp >> 44) & 0xffff
+}
+
+//当前函数的返回地址(ra), 也就是调用者; 必须内联, 且要在函数开头、调用别的函数之前读
+#[inline(always)]
+pub fn return_address() -> usize {
 	unsafe {
 		let rval;
-		llvm_asm!("csrr $0, mhartid" :"=r"(rval));
+		llvm_asm!("mv $0, ra" :"=r"(rval));
 		rval
 	}
 }
 
-pub fn mstatus_write(val: usize) {
+//本hart的编号; S态读不了mhartid, 内核里tp一直放着hart号(boot.S设置, 进trap时从TrapFrame恢复)
+#[inline(always)]
+pub fn hart_id() -> usize {
 	unsafe {
-		llvm_asm!("csrw	mstatus, $0" ::"r"(val));
+		let rval;
+		llvm_asm!("mv $0, tp" :"=r"(rval));
+		rval
 	}
 }
 
-pub fn mstatus_read() -> usize {
-	unsafe {
-		let rval;
-		llvm_asm!("csrr	$0, mstatus":"=r"(rval));
-		rval
+//下面这些是常用CSR的简写, 带位域的访问见csr.rs
+//mhartid只能在M态读
+pub fn mhartid_read() -> usize {
+	csr::mhartid::read()
+}
+
+//关掉本hart的S态中断, 返回原来的SIE位; 和irq_restore()成对使用
+pub fn irq_disable() -> usize {
+	csr::sstatus::clear(Mstatus::SIE) & Mstatus::SIE
+}
+
+pub fn irq_restore(prev: usize) {
+	if prev != 0 {
+		csr::sstatus::set(Mstatus::SIE);
 	}
 }
 
+pub fn mstatus_write(val: usize) {
+	csr::mstatus::write_bits(val);
+}
+
+pub fn mstatus_read() -> usize {
+	csr::mstatus::read_bits()
+}
+
 pub fn stvec_write(val: usize) {
-	unsafe {
-		llvm_asm!("csrw	stvec, $0" ::"r"(val));
-	}
+	csr::stvec::write_bits(val);
 }
 
 pub fn stvec_read() -> usize {
-	unsafe {
-		let rval;
-		llvm_asm!("csrr	$0, stvec" :"=r"(rval));
-		rval
-	}
+	csr::stvec::read_bits()
 }
 
 pub fn mscratch_write(val: usize) {
-	unsafe {
-		llvm_asm!("csrw	mscratch, $0" ::"r"(val));
-	}
+	csr::mscratch::write_bits(val);
 }
 
 pub fn mscratch_read() -> usize {
-	unsafe {
-		let rval;
-		llvm_asm!("csrr	$0, mscratch" : "=r"(rval));
-		rval
-	}
+	csr::mscratch::read_bits()
 }
 
 pub fn mscratch_swap(to: usize) -> usize {
-	unsafe {
-		let from;
-		llvm_asm!("csrrw	$0, mscratch, $1" : "=r"(from) : "r"(to));
-		from
-	}
+	csr::mscratch::swap(to)
 }
 
 pub fn sscratch_write(val: usize) {
-	unsafe {
-		llvm_asm!("csrw	sscratch, $0" ::"r"(val));
-	}
+	csr::sscratch::write_bits(val);
 }
 
 pub fn sscratch_read() -> usize {
-	unsafe {
-		let rval;
-		llvm_asm!("csrr	$0, sscratch" : "=r"(rval));
-		rval
-	}
+	csr::sscratch::read_bits()
 }
 
 pub fn sscratch_swap(to: usize) -> usize {
-	unsafe {
-		let from;
-		llvm_asm!("csrrw	$0, sscratch, $1" : "=r"(from) : "r"(to));
-		from
-	}
+	csr::sscratch::swap(to)
 }
 
 pub fn sepc_write(val: usize) {
-	unsafe {
-		llvm_asm!("csrw sepc, $0" :: "r"(val));
-	}
+	csr::sepc::write_bits(val);
 }
 
 pub fn sepc_read() -> usize {
-	unsafe {
-		let rval;
-		llvm_asm!("csrr $0, sepc" :"=r"(rval));
-		rval
-	}
+	csr::sepc::read_bits()
 }
 
 pub fn satp_write(val: usize) {
-	unsafe {
-		llvm_asm!("csrw satp, $0" :: "r"(val));
-	}
+	csr::satp::write_bits(val);
 }
 
 pub fn satp_read() -> usize {
-	unsafe {
-		let rval;
-		llvm_asm!("csrr $0, satp" :"=r"(rval));
-		rval
-	}
+	csr::satp::read_bits()
 }
 
 //本质会刷新整个TLB
@@ -261,10 +265,23 @@ pub fn satp_fence_asid(asid: usize) {
 	}
 }
 
-const MMIO_MTIME: *const u64 = 0x0200_BFF8 as *const u64;
+//刷新vaddr在所有ASID下的TLB, 包括全局页
+pub fn satp_fence_global(vaddr: usize) {
+	unsafe {
+		llvm_asm!("sfence.vma $0, zero" :: "r"(vaddr) :: "volatile");
+	}
+}
+
+//刷新整个TLB, 所有ASID
+pub fn satp_fence_all() {
+	unsafe {
+		llvm_asm!("sfence.vma zero, zero" :::: "volatile");
+	}
+}
 
+//mtime通过time CSR读: 在SBI固件下S态访问不了CLINT; mcounteren.TM由machine::init_hart()或固件打开
 pub fn get_mtime() -> usize {
-	unsafe { (*MMIO_MTIME) as usize }
+	csr::time::read()
 }
 
 /// Copy one data from one memory location to another.
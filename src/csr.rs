@@ -0,0 +1,658 @@
+// csr.rs
+// 带类型的CSR访问
+//
+// 每个CSR一个子模块, 比如csr::mstatus::read(); 有位域的CSR读出来是包着usize的结构体,
+// 用访问函数取各个位, 用with_xxx()在旧值上改出新值再写回去.
+// read_bits/write_bits/set/clear直接操作原始的位
+
+use crate::cpu::CpuMode;
+
+//CSR的值和原始位之间的转换, 生成子模块的宏要用
+pub trait CsrValue: Copy {
+	fn from_bits(bits: usize) -> Self;
+	fn bits(self) -> usize;
+}
+
+impl CsrValue for usize {
+	fn from_bits(bits: usize) -> Self {
+		bits
+	}
+
+	fn bits(self) -> usize {
+		self
+	}
+}
+
+//CSR编号必须是立即数, 所以每个CSR单独生成一份读写函数
+macro_rules! csr_ro {
+	($name:ident, $num:expr, $ty:ty) => {
+		pub mod $name {
+			#[allow(unused_imports)]
+			use super::*;
+
+			pub const NUM: usize = $num;
+
+			#[inline(always)]
+			pub fn read_bits() -> usize {
+				unsafe {
+					let rval;
+					llvm_asm!("csrrs $0, $1, x0" :"=r"(rval) :"i"($num) :: "volatile");
+					rval
+				}
+			}
+
+			#[inline(always)]
+			pub fn read() -> $ty {
+				<$ty as CsrValue>::from_bits(read_bits())
+			}
+		}
+	};
+}
+
+macro_rules! csr_rw {
+	($name:ident, $num:expr, $ty:ty) => {
+		pub mod $name {
+			#[allow(unused_imports)]
+			use super::*;
+
+			pub const NUM: usize = $num;
+
+			#[inline(always)]
+			pub fn read_bits() -> usize {
+				unsafe {
+					let rval;
+					llvm_asm!("csrrs $0, $1, x0" :"=r"(rval) :"i"($num) :: "volatile");
+					rval
+				}
+			}
+
+			#[inline(always)]
+			pub fn read() -> $ty {
+				<$ty as CsrValue>::from_bits(read_bits())
+			}
+
+			#[inline(always)]
+			pub fn write_bits(val: usize) {
+				unsafe {
+					llvm_asm!("csrrw x0, $0, $1" ::"i"($num), "r"(val) :: "volatile");
+				}
+			}
+
+			#[inline(always)]
+			pub fn write(val: $ty) {
+				write_bits(<$ty as CsrValue>::bits(val));
+			}
+
+			//写入新值, 返回旧值
+			#[inline(always)]
+			pub fn swap(val: usize) -> usize {
+				unsafe {
+					let rval;
+					llvm_asm!("csrrw $0, $1, $2" :"=r"(rval) :"i"($num), "r"(val) :: "volatile");
+					rval
+				}
+			}
+
+			//置位mask里的位, 返回旧值
+			#[inline(always)]
+			pub fn set(mask: usize) -> usize {
+				unsafe {
+					let rval;
+					llvm_asm!("csrrs $0, $1, $2" :"=r"(rval) :"i"($num), "r"(mask) :: "volatile");
+					rval
+				}
+			}
+
+			//清掉mask里的位, 返回旧值
+			#[inline(always)]
+			pub fn clear(mask: usize) -> usize {
+				unsafe {
+					let rval;
+					llvm_asm!("csrrc $0, $1, $2" :"=r"(rval) :"i"($num), "r"(mask) :: "volatile");
+					rval
+				}
+			}
+		}
+	};
+}
+
+//给包着usize的类型实现CsrValue
+macro_rules! csr_value {
+	($ty:ident) => {
+		impl CsrValue for $ty {
+			fn from_bits(bits: usize) -> Self {
+				$ty(bits)
+			}
+
+			fn bits(self) -> usize {
+				self.0
+			}
+		}
+	};
+}
+
+const fn with_bit(bits: usize, mask: usize, on: bool) -> usize {
+	if on { bits | mask } else { bits & !mask }
+}
+
+const fn mode_of(bits: usize) -> Option<CpuMode> {
+	match bits {
+		0 => Some(CpuMode::User),
+		1 => Some(CpuMode::Supervisor),
+		3 => Some(CpuMode::Machine),
+		_ => None,
+	}
+}
+
+//////////////////////////////////////
+// 状态寄存器
+//////////////////////////////////////
+
+//mstatus.FS/XS: 浮点单元的状态
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum FsState {
+	Off = 0,
+	Initial = 1,
+	Clean = 2,
+	Dirty = 3,
+}
+
+impl FsState {
+	const fn of(bits: usize) -> Self {
+		match bits & 0b11 {
+			0 => FsState::Off,
+			1 => FsState::Initial,
+			2 => FsState::Clean,
+			_ => FsState::Dirty,
+		}
+	}
+
+	//放在mstatus/sstatus里的位置
+	pub const fn bits(self) -> usize {
+		(self as usize) << Mstatus::FS_SHIFT
+	}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Mstatus(pub usize);
+csr_value!(Mstatus);
+
+impl Mstatus {
+	pub const SIE: usize = 1 << 1;
+	pub const MIE: usize = 1 << 3;
+	pub const SPIE: usize = 1 << 5;
+	pub const MPIE: usize = 1 << 7;
+	pub const SPP: usize = 1 << 8;
+	pub const MPP_SHIFT: usize = 11;
+	pub const MPP: usize = 0b11 << Self::MPP_SHIFT;
+	pub const FS_SHIFT: usize = 13;
+	pub const FS: usize = 0b11 << Self::FS_SHIFT;
+	pub const MPRV: usize = 1 << 17;
+	pub const SUM: usize = 1 << 18;
+	pub const MXR: usize = 1 << 19;
+	pub const TVM: usize = 1 << 20;
+	pub const TW: usize = 1 << 21;
+	pub const TSR: usize = 1 << 22;
+
+	pub const fn new() -> Self {
+		Mstatus(0)
+	}
+
+	pub const fn bits(self) -> usize {
+		self.0
+	}
+
+	pub const fn sie(self) -> bool { self.0 & Self::SIE != 0 }
+	pub const fn mie(self) -> bool { self.0 & Self::MIE != 0 }
+	pub const fn spie(self) -> bool { self.0 & Self::SPIE != 0 }
+	pub const fn mpie(self) -> bool { self.0 & Self::MPIE != 0 }
+	pub const fn mprv(self) -> bool { self.0 & Self::MPRV != 0 }
+	pub const fn sum(self) -> bool { self.0 & Self::SUM != 0 }
+	pub const fn mxr(self) -> bool { self.0 & Self::MXR != 0 }
+
+	//trap之前的特权级; 2是保留值(以前的H态), 返回None
+	pub const fn mpp(self) -> Option<CpuMode> {
+		mode_of((self.0 & Self::MPP) >> Self::MPP_SHIFT)
+	}
+
+	pub const fn spp(self) -> CpuMode {
+		if self.0 & Self::SPP != 0 { CpuMode::Supervisor } else { CpuMode::User }
+	}
+
+	pub const fn fs(self) -> FsState {
+		FsState::of(self.0 >> Self::FS_SHIFT)
+	}
+
+	pub const fn with_sie(self, on: bool) -> Self { Mstatus(with_bit(self.0, Self::SIE, on)) }
+	pub const fn with_mie(self, on: bool) -> Self { Mstatus(with_bit(self.0, Self::MIE, on)) }
+	pub const fn with_spie(self, on: bool) -> Self { Mstatus(with_bit(self.0, Self::SPIE, on)) }
+	pub const fn with_mpie(self, on: bool) -> Self { Mstatus(with_bit(self.0, Self::MPIE, on)) }
+	pub const fn with_mprv(self, on: bool) -> Self { Mstatus(with_bit(self.0, Self::MPRV, on)) }
+	pub const fn with_sum(self, on: bool) -> Self { Mstatus(with_bit(self.0, Self::SUM, on)) }
+
+	pub const fn with_mpp(self, mode: CpuMode) -> Self {
+		Mstatus(self.0 & !Self::MPP | (mode as usize) << Self::MPP_SHIFT)
+	}
+
+	pub const fn with_spp(self, mode: CpuMode) -> Self {
+		Mstatus(with_bit(self.0, Self::SPP, mode as usize != 0))
+	}
+
+	pub const fn with_fs(self, fs: FsState) -> Self {
+		Mstatus(self.0 & !Self::FS | fs.bits())
+	}
+}
+
+//sstatus是mstatus在S态能看到的那部分, 位的位置相同
+#[derive(Clone, Copy, PartialEq)]
+pub struct Sstatus(pub usize);
+csr_value!(Sstatus);
+
+impl Sstatus {
+	pub const fn new() -> Self {
+		Sstatus(0)
+	}
+
+	pub const fn bits(self) -> usize {
+		self.0
+	}
+
+	pub const fn sie(self) -> bool { self.0 & Mstatus::SIE != 0 }
+	pub const fn spie(self) -> bool { self.0 & Mstatus::SPIE != 0 }
+	pub const fn sum(self) -> bool { self.0 & Mstatus::SUM != 0 }
+	pub const fn mxr(self) -> bool { self.0 & Mstatus::MXR != 0 }
+
+	pub const fn spp(self) -> CpuMode {
+		if self.0 & Mstatus::SPP != 0 { CpuMode::Supervisor } else { CpuMode::User }
+	}
+
+	pub const fn fs(self) -> FsState {
+		FsState::of(self.0 >> Mstatus::FS_SHIFT)
+	}
+
+	pub const fn with_sie(self, on: bool) -> Self { Sstatus(with_bit(self.0, Mstatus::SIE, on)) }
+	pub const fn with_spie(self, on: bool) -> Self { Sstatus(with_bit(self.0, Mstatus::SPIE, on)) }
+	pub const fn with_sum(self, on: bool) -> Self { Sstatus(with_bit(self.0, Mstatus::SUM, on)) }
+
+	pub const fn with_spp(self, mode: CpuMode) -> Self {
+		Sstatus(with_bit(self.0, Mstatus::SPP, mode as usize != 0))
+	}
+
+	pub const fn with_fs(self, fs: FsState) -> Self {
+		Sstatus(self.0 & !Mstatus::FS | fs.bits())
+	}
+}
+
+//////////////////////////////////////
+// 中断和异常
+//////////////////////////////////////
+
+//mcause/scause最高位为1时的原因号, 也是mie/mip/mideleg里对应的位号
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Interrupt {
+	SupervisorSoft = 1,
+	MachineSoft = 3,
+	SupervisorTimer = 5,
+	MachineTimer = 7,
+	SupervisorExternal = 9,
+	MachineExternal = 11,
+}
+
+impl Interrupt {
+	pub const fn from_code(code: usize) -> Option<Self> {
+		match code {
+			1 => Some(Interrupt::SupervisorSoft),
+			3 => Some(Interrupt::MachineSoft),
+			5 => Some(Interrupt::SupervisorTimer),
+			7 => Some(Interrupt::MachineTimer),
+			9 => Some(Interrupt::SupervisorExternal),
+			11 => Some(Interrupt::MachineExternal),
+			_ => None,
+		}
+	}
+
+	pub const fn mask(self) -> usize {
+		1 << self as usize
+	}
+}
+
+//mcause/scause最高位为0时的原因号, 也是medeleg里对应的位号
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Exception {
+	InstructionMisaligned = 0,
+	InstructionFault = 1,
+	IllegalInstruction = 2,
+	Breakpoint = 3,
+	LoadMisaligned = 4,
+	LoadFault = 5,
+	StoreMisaligned = 6,
+	StoreFault = 7,
+	UserEcall = 8,
+	SupervisorEcall = 9,
+	MachineEcall = 11,
+	InstructionPageFault = 12,
+	LoadPageFault = 13,
+	StorePageFault = 15,
+}
+
+impl Exception {
+	pub const fn from_code(code: usize) -> Option<Self> {
+		match code {
+			0 => Some(Exception::InstructionMisaligned),
+			1 => Some(Exception::InstructionFault),
+			2 => Some(Exception::IllegalInstruction),
+			3 => Some(Exception::Breakpoint),
+			4 => Some(Exception::LoadMisaligned),
+			5 => Some(Exception::LoadFault),
+			6 => Some(Exception::StoreMisaligned),
+			7 => Some(Exception::StoreFault),
+			8 => Some(Exception::UserEcall),
+			9 => Some(Exception::SupervisorEcall),
+			11 => Some(Exception::MachineEcall),
+			12 => Some(Exception::InstructionPageFault),
+			13 => Some(Exception::LoadPageFault),
+			15 => Some(Exception::StorePageFault),
+			_ => None,
+		}
+	}
+
+	pub const fn mask(self) -> usize {
+		1 << self as usize
+	}
+}
+
+//mcause/scause
+#[derive(Clone, Copy, PartialEq)]
+pub struct Cause(pub usize);
+csr_value!(Cause);
+
+impl Cause {
+	pub const INTERRUPT: usize = 1 << 63;
+
+	pub const fn is_interrupt(self) -> bool {
+		self.0 & Self::INTERRUPT != 0
+	}
+
+	//去掉最高位的原因号
+	pub const fn code(self) -> usize {
+		self.0 & !Self::INTERRUPT
+	}
+
+	//不是中断或者原因号不认识都返回None
+	pub const fn interrupt(self) -> Option<Interrupt> {
+		if self.is_interrupt() { Interrupt::from_code(self.code()) } else { None }
+	}
+
+	pub const fn exception(self) -> Option<Exception> {
+		if self.is_interrupt() { None } else { Exception::from_code(self.code()) }
+	}
+}
+
+//mie/mip/sie/sip/mideleg: 每种中断一位
+#[derive(Clone, Copy, PartialEq)]
+pub struct Interrupts(pub usize);
+csr_value!(Interrupts);
+
+impl Interrupts {
+	pub const fn new() -> Self {
+		Interrupts(0)
+	}
+
+	pub const fn bits(self) -> usize {
+		self.0
+	}
+
+	pub const fn has(self, irq: Interrupt) -> bool {
+		self.0 & irq.mask() != 0
+	}
+
+	pub const fn with(self, irq: Interrupt) -> Self {
+		Interrupts(self.0 | irq.mask())
+	}
+
+	pub const fn without(self, irq: Interrupt) -> Self {
+		Interrupts(self.0 & !irq.mask())
+	}
+}
+
+//medeleg: 每种异常一位
+#[derive(Clone, Copy, PartialEq)]
+pub struct Exceptions(pub usize);
+csr_value!(Exceptions);
+
+impl Exceptions {
+	pub const fn new() -> Self {
+		Exceptions(0)
+	}
+
+	pub const fn bits(self) -> usize {
+		self.0
+	}
+
+	pub const fn has(self, e: Exception) -> bool {
+		self.0 & e.mask() != 0
+	}
+
+	pub const fn with(self, e: Exception) -> Self {
+		Exceptions(self.0 | e.mask())
+	}
+
+	pub const fn without(self, e: Exception) -> Self {
+		Exceptions(self.0 & !e.mask())
+	}
+}
+
+//mtvec/stvec: 低两位是模式, 其余是向量基址(4字节对齐)
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum TvecMode {
+	Direct = 0,
+	//异步中断跳到 BASE + 4 * 原因号
+	Vectored = 1,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Tvec(pub usize);
+csr_value!(Tvec);
+
+impl Tvec {
+	pub const fn new(base: usize, mode: TvecMode) -> Self {
+		Tvec(base & !0b11 | mode as usize)
+	}
+
+	pub const fn base(self) -> usize {
+		self.0 & !0b11
+	}
+
+	pub const fn mode(self) -> TvecMode {
+		if self.0 & 0b11 == 1 { TvecMode::Vectored } else { TvecMode::Direct }
+	}
+}
+
+//////////////////////////////////////
+// PMP
+//////////////////////////////////////
+
+//pmpNcfg的A域: 这一项怎么匹配地址
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum PmpMatch {
+	Off = 0,
+	//pmpaddr[N-1] <= addr < pmpaddr[N]
+	Tor = 1,
+	Na4 = 2,
+	Napot = 3,
+}
+
+//pmpcfg里的一项, 一个字节
+#[derive(Clone, Copy, PartialEq)]
+pub struct Pmp(pub u8);
+
+impl Pmp {
+	pub const R: u8 = 1 << 0;
+	pub const W: u8 = 1 << 1;
+	pub const X: u8 = 1 << 2;
+	pub const A_SHIFT: u8 = 3;
+	pub const L: u8 = 1 << 7;
+
+	pub const fn new(perm: u8, m: PmpMatch) -> Self {
+		Pmp(perm & (Self::R | Self::W | Self::X) | (m as u8) << Self::A_SHIFT)
+	}
+
+	pub const fn readable(self) -> bool { self.0 & Self::R != 0 }
+	pub const fn writable(self) -> bool { self.0 & Self::W != 0 }
+	pub const fn executable(self) -> bool { self.0 & Self::X != 0 }
+	pub const fn locked(self) -> bool { self.0 & Self::L != 0 }
+
+	pub const fn matching(self) -> PmpMatch {
+		match (self.0 >> Self::A_SHIFT) & 0b11 {
+			0 => PmpMatch::Off,
+			1 => PmpMatch::Tor,
+			2 => PmpMatch::Na4,
+			_ => PmpMatch::Napot,
+		}
+	}
+}
+
+//RV64上pmpcfg0装第0-7项, pmpcfg2装第8-15项
+#[derive(Clone, Copy, PartialEq)]
+pub struct PmpCfg(pub usize);
+csr_value!(PmpCfg);
+
+impl PmpCfg {
+	pub const fn new() -> Self {
+		PmpCfg(0)
+	}
+
+	pub const fn entry(self, i: usize) -> Pmp {
+		Pmp((self.0 >> (i % 8 * 8)) as u8)
+	}
+
+	pub const fn with_entry(self, i: usize, pmp: Pmp) -> Self {
+		let shift = i % 8 * 8;
+		PmpCfg(self.0 & !(0xff << shift) | (pmp.0 as usize) << shift)
+	}
+}
+
+//////////////////////////////////////
+// 计数器
+//////////////////////////////////////
+
+//mcounteren/scounteren: 下一级特权能不能读cycle/time/instret/hpmcounterN
+#[derive(Clone, Copy, PartialEq)]
+pub struct Counteren(pub usize);
+csr_value!(Counteren);
+
+impl Counteren {
+	pub const CY: usize = 1 << 0;
+	pub const TM: usize = 1 << 1;
+	pub const IR: usize = 1 << 2;
+
+	//hpmcounterN(3-31)对应第N位
+	pub const fn hpm(self, n: usize) -> bool { self.0 & 1 << n != 0 }
+	pub const fn with_hpm(self, n: usize, on: bool) -> Self { Counteren(with_bit(self.0, 1 << n, on)) }
+
+	pub const fn new() -> Self {
+		Counteren(0)
+	}
+
+	pub const fn cy(self) -> bool { self.0 & Self::CY != 0 }
+	pub const fn tm(self) -> bool { self.0 & Self::TM != 0 }
+	pub const fn ir(self) -> bool { self.0 & Self::IR != 0 }
+
+	pub const fn with_cy(self, on: bool) -> Self { Counteren(with_bit(self.0, Self::CY, on)) }
+	pub const fn with_tm(self, on: bool) -> Self { Counteren(with_bit(self.0, Self::TM, on)) }
+	pub const fn with_ir(self, on: bool) -> Self { Counteren(with_bit(self.0, Self::IR, on)) }
+}
+
+//////////////////////////////////////
+// M态CSR
+//////////////////////////////////////
+csr_ro!(mvendorid, 0xf11, usize);
+csr_ro!(marchid, 0xf12, usize);
+csr_ro!(mimpid, 0xf13, usize);
+csr_ro!(mhartid, 0xf14, usize);
+
+csr_rw!(mstatus, 0x300, Mstatus);
+csr_rw!(misa, 0x301, usize);
+csr_rw!(medeleg, 0x302, Exceptions);
+csr_rw!(mideleg, 0x303, Interrupts);
+csr_rw!(mie, 0x304, Interrupts);
+csr_rw!(mtvec, 0x305, Tvec);
+csr_rw!(mcounteren, 0x306, Counteren);
+
+csr_rw!(mscratch, 0x340, usize);
+csr_rw!(mepc, 0x341, usize);
+csr_rw!(mcause, 0x342, Cause);
+csr_rw!(mtval, 0x343, usize);
+csr_rw!(mip, 0x344, Interrupts);
+
+csr_rw!(pmpcfg0, 0x3a0, PmpCfg);
+csr_rw!(pmpcfg2, 0x3a2, PmpCfg);
+csr_rw!(pmpaddr0, 0x3b0, usize);
+csr_rw!(pmpaddr1, 0x3b1, usize);
+csr_rw!(pmpaddr2, 0x3b2, usize);
+csr_rw!(pmpaddr3, 0x3b3, usize);
+csr_rw!(pmpaddr4, 0x3b4, usize);
+csr_rw!(pmpaddr5, 0x3b5, usize);
+csr_rw!(pmpaddr6, 0x3b6, usize);
+csr_rw!(pmpaddr7, 0x3b7, usize);
+
+csr_rw!(mcycle, 0xb00, usize);
+csr_rw!(minstret, 0xb02, usize);
+//可编程计数器, 计什么由mhpmeventN决定(事件号由实现定义, 0表示不计数)
+csr_rw!(mhpmcounter3, 0xb03, usize);
+csr_rw!(mhpmcounter4, 0xb04, usize);
+csr_rw!(mhpmcounter5, 0xb05, usize);
+csr_rw!(mhpmcounter6, 0xb06, usize);
+csr_rw!(mhpmevent3, 0x323, usize);
+csr_rw!(mhpmevent4, 0x324, usize);
+csr_rw!(mhpmevent5, 0x325, usize);
+csr_rw!(mhpmevent6, 0x326, usize);
+
+//设置第i(0-7)项PMP: pmpaddr里放的是地址右移2位
+pub fn pmp_set(i: usize, pmp: Pmp, addr: usize) {
+	match i {
+		0 => pmpaddr0::write_bits(addr >> 2),
+		1 => pmpaddr1::write_bits(addr >> 2),
+		2 => pmpaddr2::write_bits(addr >> 2),
+		3 => pmpaddr3::write_bits(addr >> 2),
+		4 => pmpaddr4::write_bits(addr >> 2),
+		5 => pmpaddr5::write_bits(addr >> 2),
+		6 => pmpaddr6::write_bits(addr >> 2),
+		7 => pmpaddr7::write_bits(addr >> 2),
+		_ => panic!("pmp_set: only entries 0-7 are supported"),
+	}
+	pmpcfg0::write(pmpcfg0::read().with_entry(i, pmp));
+}
+
+//////////////////////////////////////
+// S态CSR
+//////////////////////////////////////
+csr_rw!(sstatus, 0x100, Sstatus);
+csr_rw!(sie, 0x104, Interrupts);
+csr_rw!(stvec, 0x105, Tvec);
+csr_rw!(scounteren, 0x106, Counteren);
+
+csr_rw!(sscratch, 0x140, usize);
+csr_rw!(sepc, 0x141, usize);
+csr_rw!(scause, 0x142, Cause);
+csr_rw!(stval, 0x143, usize);
+csr_rw!(sip, 0x144, Interrupts);
+
+//satp的各个域用cpu.rs里的build_satp/satp_root/satp_asid
+csr_rw!(satp, 0x180, usize);
+
+//////////////////////////////////////
+// 用户态可读的计数器
+//////////////////////////////////////
+csr_ro!(cycle, 0xc00, usize);
+csr_ro!(time, 0xc01, usize);
+csr_ro!(instret, 0xc02, usize);
+csr_ro!(hpmcounter3, 0xc03, usize);
+csr_ro!(hpmcounter4, 0xc04, usize);
+csr_ro!(hpmcounter5, 0xc05, usize);
+csr_ro!(hpmcounter6, 0xc06, usize);
@@ -0,0 +1,105 @@
+// fpu.rs
+// 浮点上下文的延迟保存/恢复
+//
+// sstatus.FS: 0 Off, 1 Initial, 2 Clean, 3 Dirty
+// 进程开始时FS=Off, 第一次用浮点指令会触发非法指令异常, 这时才给它打开FPU;
+// 进trap时只有FS=Dirty才把浮点寄存器和fcsr存进TrapFrame(见trap.S), 之后置为Clean;
+// 切换进程时只有用过FPU的进程才恢复, 这个hart上的寄存器本来就是它的也不用恢复
+
+use crate::cpu::{TrapFrame, MAX_HARTS};
+use crate::csr::{self, FsState, Sstatus};
+use crate::process::user_virt_to_phys;
+
+//TrapFrame.fs里存的是sstatus的FS域原来的位, switch_to_user直接把它或进sstatus
+pub const FS_OFF: usize = FsState::Off.bits();
+pub const FS_CLEAN: usize = FsState::Clean.bits();
+
+//每个hart的浮点寄存器现在装的是哪个TrapFrame的状态, 0表示谁的都不是
+static mut FP_OWNER: [usize; MAX_HARTS] = [0; MAX_HARTS];
+
+extern "C" {
+	fn load_fp_state(frame: usize);
+}
+
+//浮点指令, 包括压缩的c.fld/c.fsd/c.fldsp/c.fsdsp和访问fflags/frm/fcsr的csr指令
+fn is_fp_insn(insn: usize) -> bool {
+	if insn & 0b11 != 0b11 {
+		let quadrant = insn & 0b11;
+		let funct3 = (insn >> 13) & 0b111;
+		return (quadrant == 0b00 || quadrant == 0b10) && (funct3 == 0b001 || funct3 == 0b101);
+	}
+	match insn & 0x7f {
+		0x07 | 0x27 | 0x43 | 0x47 | 0x4b | 0x4f | 0x53 => true,
+		0x73 => {
+			let funct3 = (insn >> 12) & 0b111;
+			let csr = insn >> 20;
+			funct3 != 0 && funct3 != 0b100 && csr >= 1 && csr <= 3
+		},
+		_ => false,
+	}
+}
+
+//按半字读, 32位指令的后一半可能在下一页
+unsafe fn read_insn(pid: u16, epc: usize) -> Option<usize> {
+	let lo = (user_virt_to_phys(pid, epc, false)? as *const u16).read_volatile() as usize;
+	if lo & 0b11 != 0b11 {
+		return Some(lo);
+	}
+	let hi = (user_virt_to_phys(pid, epc + 2, false)? as *const u16).read_volatile() as usize;
+	Some(lo | hi << 16)
+}
+
+//把frame的浮点状态装进本hart的寄存器, 之后FS=Clean
+unsafe fn restore(frame: *mut TrapFrame, hart: usize) {
+	csr::sstatus::set(FS_CLEAN);
+	load_fp_state(frame as usize);
+	//在别的hart上装着的是旧状态了
+	release(frame);
+	FP_OWNER[hart] = frame as usize;
+}
+
+//非法指令异常里调用; tval是出错的指令(硬件没给就从用户内存里读)
+//是进程第一次用FPU就打开它, 返回true, 重新执行这条指令
+pub fn first_use(frame: *mut TrapFrame, hart: usize, epc: usize, tval: usize, status: usize) -> bool {
+	unsafe {
+		if (*frame).fs != FS_OFF || Sstatus(status).fs() != FsState::Off {
+			return false;
+		}
+		let insn = if tval != 0 {
+			tval
+		}else{
+			match read_insn((*frame).pid as u16, epc) {
+				Some(insn) => insn,
+				None => return false,
+			}
+		};
+		if !is_fp_insn(insn) {
+			return false;
+		}
+		//fregs和fcsr在TrapFrame分配时已清零
+		(*frame).fs = FS_CLEAN;
+		restore(frame, hart);
+		true
+	}
+}
+
+//切换到frame之前调用, 之后switch_to_user按frame.fs设置sstatus.FS
+pub fn switch(frame: *mut TrapFrame, hart: usize) {
+	unsafe {
+		if (*frame).fs == FS_OFF || FP_OWNER[hart] == frame as usize {
+			return;
+		}
+		restore(frame, hart);
+	}
+}
+
+//进程销毁时调用, TrapFrame的内存之后可能分给别的进程
+pub fn release(frame: *mut TrapFrame) {
+	unsafe {
+		for owner in FP_OWNER.iter_mut() {
+			if *owner == frame as usize {
+				*owner = 0;
+			}
+		}
+	}
+}
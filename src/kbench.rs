@@ -0,0 +1,131 @@
+//kmem压力测试: 同一串随机的分配/释放分别跑在kmem和原来的first-fit实现上, 比较用时
+//cargo build --features kmem-bench, kmain()里会运行一次
+use crate::cpu::get_mtime;
+use crate::kmem::{kfree, kmalloc};
+use crate::page::{align_val, dealloc, zalloc, PAGE_SIZE};
+use core::ptr::null_mut;
+
+//同时存活的分配个数
+const SLOTS: usize = 128;
+const ROUNDS: usize = 20000;
+//first-fit用原来kmem一样大的64页
+const REF_PAGES: usize = 64;
+
+//线性同余随机数, 两边用同一个种子得到同一串操作
+struct Lcg(u64);
+
+impl Lcg {
+	fn next(&mut self) -> usize {
+		self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+		(self.0 >> 33) as usize
+	}
+}
+
+//大多数是小对象, 偶尔有大的
+fn pick_size(r: usize) -> usize {
+	match r % 16 {
+		0 => 1024 + r % 3072,
+		1..=3 => 256 + r % 768,
+		_ => 16 + r % 240,
+	}
+}
+
+//原来的kmem: 分配时从头扫描, 释放时扫描整个堆合并
+const TAKEN: usize = 1 << 63;
+
+struct FirstFit {
+	base: usize,
+	len:  usize,
+}
+
+impl FirstFit {
+	unsafe fn alloc(&mut self, sz: usize) -> *mut u8 {
+		let size = align_val(sz, 3) + 8;
+		let tail = self.base + self.len;
+		let mut head = self.base;
+		while head < tail {
+			let h = *(head as *mut usize);
+			let chunk = h & !TAKEN;
+			if h & TAKEN == 0 && size <= chunk {
+				let rem = chunk - size;
+				if rem > 8 {
+					*((head + size) as *mut usize) = rem;
+					*(head as *mut usize) = size | TAKEN;
+				}else{
+					*(head as *mut usize) = chunk | TAKEN;
+				}
+				return (head + 8) as *mut u8;
+			}
+			head += chunk;
+		}
+		null_mut()
+	}
+
+	unsafe fn free(&mut self, ptr: *mut u8) {
+		*((ptr as usize - 8) as *mut usize) &= !TAKEN;
+		let tail = self.base + self.len;
+		let mut head = self.base;
+		while head < tail {
+			let h = *(head as *mut usize);
+			let next = head + (h & !TAKEN);
+			if h & !TAKEN == 0 || next >= tail {
+				break;
+			}
+			let n = *(next as *mut usize);
+			if h & TAKEN == 0 && n & TAKEN == 0 {
+				*(head as *mut usize) = h + n;
+			}
+			head += *(head as *mut usize) & !TAKEN;
+		}
+	}
+}
+
+//返回(用时mtime ticks, 失败次数)
+fn stress(alloc: &mut dyn FnMut(usize) -> *mut u8, free: &mut dyn FnMut(*mut u8)) -> (usize, usize) {
+	let mut slots = [null_mut::<u8>(); SLOTS];
+	let mut rng = Lcg(0x5eed);
+	let mut fails = 0;
+	let start = get_mtime();
+	for _ in 0..ROUNDS {
+		let r = rng.next();
+		let i = r % SLOTS;
+		if slots[i].is_null() {
+			let p = alloc(pick_size(r >> 8));
+			if p.is_null() {
+				fails += 1;
+			}else{
+				unsafe { *p = i as u8; }
+			}
+			slots[i] = p;
+		}else{
+			free(slots[i]);
+			slots[i] = null_mut();
+		}
+	}
+	for p in slots.iter() {
+		if !p.is_null() {
+			free(*p);
+		}
+	}
+	(get_mtime() - start, fails)
+}
+
+pub fn run() {
+	println!("~~~~~KMEM Bench: {} rounds, {} live slots~~~~~", ROUNDS, SLOTS);
+
+	let (ticks, fails) = stress(&mut |sz| kmalloc(sz), &mut |p| kfree(p));
+	println!("{:<12} {:>10} ticks {:>6} failed", "kmem", ticks, fails);
+
+	let base = zalloc(REF_PAGES);
+	assert!(!base.is_null());
+	let mut ff = FirstFit { base: base as usize, len: REF_PAGES * PAGE_SIZE };
+	unsafe {
+		*(base as *mut usize) = REF_PAGES * PAGE_SIZE;
+	}
+	let ff = &mut ff as *mut FirstFit;
+	let (ticks, fails) = stress(&mut |sz| unsafe { (*ff).alloc(sz) }, &mut |p| unsafe { (*ff).free(p) });
+	println!("{:<12} {:>10} ticks {:>6} failed", "first-fit", ticks, fails);
+	dealloc(base);
+
+	println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+}
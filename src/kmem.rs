@@ -1,9 +1,13 @@
-use crate::page::{align_val, zalloc, Table, PAGE_SIZE};
-use core::{mem::size_of, ptr::null_mut};
+use crate::cpu::{hart_id, irq_disable, irq_restore, return_address, MAX_HARTS};
+use crate::backtrace;
+use crate::lock::Mutex;
+use crate::page::{align_val, alloc, block_of, dealloc, zalloc, Table, PAGE_SIZE};
+use core::{mem::size_of, ptr::null_mut, sync::atomic::{AtomicUsize, Ordering}};
 
 #[repr(usize)]
 enum AllocListFlags {
 	Taken = 1 << 63,
+	PrevTaken = 1 << 62, //前面相邻的chunk已分配, 它没有尾标记
 }
 impl AllocListFlags {
 	pub fn val(self) -> usize {
@@ -11,6 +15,11 @@ impl AllocListFlags {
 	}
 }
 
+const FLAGS_MASK: usize = AllocListFlags::Taken as usize | AllocListFlags::PrevTaken as usize;
+
+//boundary tag: 每个chunk开头是AllocList(大小+标志)
+//空闲chunk的数据区放空闲链表指针, 最后一个字是尾标记(再存一次大小),
+//释放时由此找到前一个相邻chunk, 合并都是O(1)
 struct AllocList {
 	pub flags_size: usize,
 }
@@ -28,51 +37,184 @@ impl AllocList {
 	pub fn set_free(&mut self) {
 		self.flags_size &= !AllocListFlags::Taken.val();
 	}
+	pub fn is_prev_taken(&self) -> bool {
+		self.flags_size & AllocListFlags::PrevTaken.val() != 0
+	}
+	pub fn set_prev_taken(&mut self) {
+		self.flags_size |= AllocListFlags::PrevTaken.val();
+	}
+	pub fn clear_prev_taken(&mut self) {
+		self.flags_size &= !AllocListFlags::PrevTaken.val();
+	}
 	pub fn set_size(&mut self, sz: usize) {
-		let k = self.is_taken();
-		self.flags_size = sz & !AllocListFlags::Taken.val();
-		if k {
-			self.flags_size |= AllocListFlags::Taken.val();
-		}
+		self.flags_size = (sz & !FLAGS_MASK) | (self.flags_size & FLAGS_MASK);
 	}
 	pub fn get_size(&self) -> usize {
-		self.flags_size & !AllocListFlags::Taken.val()
+		self.flags_size & !FLAGS_MASK
+	}
+}
+
+//空闲chunk: 头 + 空闲链表的双向指针, 尾标记在chunk最后
+struct FreeChunk {
+	head: AllocList,
+	next: *mut FreeChunk,
+	prev: *mut FreeChunk,
+}
+
+//最小的chunk要放得下头、两个指针和尾标记
+const MIN_CHUNK: usize = size_of::<FreeChunk>() + size_of::<usize>();
+//分离空闲链表: 第k个链表放大小在[32 << k, 64 << k)的chunk, 最后一个不设上限
+const NUM_CLASSES: usize = 16;
+static mut FREE_LISTS: [*mut FreeChunk; NUM_CLASSES] = [null_mut(); NUM_CLASSES];
+
+fn size_class(size: usize) -> usize {
+	let lg = (usize::max_value().count_ones() - 1 - size.leading_zeros()) as usize;
+	(lg - MIN_CHUNK.trailing_zeros() as usize).min(NUM_CLASSES - 1)
+}
+
+unsafe fn next_chunk(c: *mut AllocList) -> *mut AllocList {
+	(c as *mut u8).add((*c).get_size()) as *mut AllocList
+}
+
+//只有前一个chunk空闲(有尾标记)时才能用
+unsafe fn prev_chunk(c: *mut AllocList) -> *mut AllocList {
+	let size = *(c as *mut usize).offset(-1);
+	(c as *mut u8).sub(size) as *mut AllocList
+}
+
+unsafe fn free_list_push(c: *mut AllocList) {
+	let f = c as *mut FreeChunk;
+	let class = size_class((*c).get_size());
+	(*f).prev = null_mut();
+	(*f).next = FREE_LISTS[class];
+	if !(*f).next.is_null() {
+		(*(*f).next).prev = f;
 	}
+	FREE_LISTS[class] = f;
 }
 
-static mut KMEM_HEAD: *mut AllocList = null_mut();
+unsafe fn free_list_remove(c: *mut AllocList) {
+	let f = c as *mut FreeChunk;
+	if (*f).prev.is_null() {
+		FREE_LISTS[size_class((*c).get_size())] = (*f).next;
+	}else{
+		(*(*f).prev).next = (*f).next;
+	}
+	if !(*f).next.is_null() {
+		(*(*f).next).prev = (*f).prev;
+	}
+}
+
+//把c变成大小为size的空闲chunk: 写尾标记, 挂进链表, 告诉后一个chunk
+//保留c自己的PrevTaken位
+unsafe fn make_free(c: *mut AllocList, size: usize) {
+	(*c).set_free();
+	(*c).set_size(size);
+	*((c as *mut u8).add(size) as *mut usize).offset(-1) = size;
+	free_list_push(c);
+	(*next_chunk(c)).clear_prev_taken();
+}
+
+//内核堆由若干块连续页(region)组成, 不够时向页分配器再要
+//每个region开头是KmemRegion结构, 后面是chunks, 最后一个字是已分配、大小为0的结束标记
+struct KmemRegion {
+	next:  *mut KmemRegion,
+	pages: usize,
+}
+
+impl KmemRegion {
+	unsafe fn head(&self) -> *mut AllocList {
+		(self as *const KmemRegion).add(1) as *mut AllocList
+	}
+	//结束标记
+	unsafe fn tail(&self) -> *mut AllocList {
+		(self as *const KmemRegion as *mut u8).add(self.pages * PAGE_SIZE - size_of::<AllocList>()) as *mut AllocList
+	}
+	//整个region只剩一个空闲chunk
+	unsafe fn is_empty(&self) -> bool {
+		let head = self.head();
+		(*head).is_free() && next_chunk(head) == self.tail()
+	}
+}
+
+// 64 * 4096 = 262K, 第一个region, 永远不还
+const KMEM_INIT_PAGES: usize = 64;
+//每次至少扩大这么多页
+const KMEM_GROW_PAGES: usize = 16;
+
+//保护region链表、空闲链表和所有chunk; 小对象走slab, 不经过这里
+static mut KMEM_LOCK: Mutex = Mutex::new();
+static mut KMEM_REGIONS: *mut KmemRegion = null_mut();
 static mut KMEM_ALLOC: usize = 0;
 static mut KMEM_PAGE_TABLE: *mut Table = null_mut();
 
 pub fn get_head() -> *mut u8 {
-	unsafe { KMEM_HEAD as *mut u8 }
+	unsafe { (*KMEM_REGIONS).head() as *mut u8 }
 }
 
 pub fn get_page_table() -> *mut Table {
 	unsafe { KMEM_PAGE_TABLE as *mut Table }
 }
 
+//内核堆当前占用的页数
 pub fn get_num_allocations() -> usize {
 	unsafe { KMEM_ALLOC }
 }
 
+//向页分配器要一个新的region, 挂到链表末尾
+unsafe fn grow(pages: usize) -> *mut KmemRegion {
+	let r = alloc(pages) as *mut KmemRegion;
+	if r.is_null() {
+		return null_mut();
+	}
+	(*r).next = null_mut();
+	(*r).pages = pages;
+	let tail = (*r).tail();
+	(*tail).flags_size = 0;
+	(*tail).set_taken();
+	let head = (*r).head();
+	(*head).flags_size = 0;
+	(*head).set_prev_taken();
+	make_free(head, tail as usize - head as usize);
+
+	if KMEM_REGIONS.is_null() {
+		KMEM_REGIONS = r;
+	}else{
+		let mut last = KMEM_REGIONS;
+		while !(*last).next.is_null() {
+			last = (*last).next;
+		}
+		(*last).next = r;
+	}
+	KMEM_ALLOC += pages;
+	r
+}
+
 // Initialize kernel's memory
 pub fn init() {
 	unsafe {
-		// 64 * 4096 = 262K
-		let k_alloc = zalloc(64);
-		assert!(!k_alloc.is_null());
-		KMEM_ALLOC = 64;
-		KMEM_HEAD = k_alloc as *mut AllocList;
-		(*KMEM_HEAD).set_free();
-		(*KMEM_HEAD).set_size(KMEM_ALLOC * PAGE_SIZE);
+		let r = grow(KMEM_INIT_PAGES);
+		assert!(!r.is_null());
 		KMEM_PAGE_TABLE = zalloc(1) as *mut Table;
 	}
 }
 
+//公开的分配函数都记下调用者(返回地址), kmem-harden时存进chunk里
+#[inline(never)]
 pub fn kzmalloc(sz: usize) -> *mut u8{
+	let ra = return_address();
+	kzmalloc_from(sz, size_of::<AllocList>(), ra)
+}
+
+#[inline(never)]
+pub fn kzmalloc_aligned(sz: usize, align: usize) -> *mut u8 {
+	let ra = return_address();
+	kzmalloc_from(sz, align, ra)
+}
+
+fn kzmalloc_from(sz: usize, align: usize, ra: usize) -> *mut u8 {
 	let size = align_val(sz, 3);
-	let ret = kmalloc(size);
+	let ret = kmalloc_from(size, align, ra);
 
 	if !ret.is_null() {
 		for i in 0..size {
@@ -84,86 +226,1046 @@ pub fn kzmalloc(sz: usize) -> *mut u8{
 	ret
 }
 
+#[inline(never)]
 pub fn kmalloc(sz: usize) -> *mut u8 {
-	unsafe {
-				//上舍入 整8字节, 还有一个8字节的AllocList结构
-		let size = align_val(sz, 3) + size_of::<AllocList>();
-		let mut head = KMEM_HEAD;
+	let ra = return_address();
+	kmalloc_from(sz, size_of::<AllocList>(), ra)
+}
 
-		//指针移动到kernel内存结尾
-		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
-		while head < tail {
-			if (*head).is_free() && size <= (*head).get_size() {
-				let chunk_size = (*head).get_size();
-				let rem = chunk_size - size;
-				(*head).set_taken();
-				if rem > size_of::<AllocList>() {
-					//剩余的内核空间
-					let next = (head as *mut u8).add(size) as *mut AllocList;
-					(*next).set_free();
-					(*next).set_size(rem);
-
-					(*head).set_size(size);
-				}else{
-					//剩余空间太小，全部取走
-					(*head).set_size(chunk_size);
+//align必须是2的幂; 返回的地址按align对齐
+//堆里放不下就扩大堆; 页也要不到时返回空指针, 由调用者处理
+#[inline(never)]
+pub fn kmalloc_aligned(sz: usize, align: usize) -> *mut u8 {
+	let ra = return_address();
+	kmalloc_from(sz, align, ra)
+}
+
+//已分配chunk里数据前面的部分: AllocList, kmem-harden时还有红区(RedZone)
+const CHUNK_HDR: usize = size_of::<AllocList>() + if cfg!(feature = "kmem-harden") { 3 * size_of::<usize>() } else { 0 };
+//数据后面的部分: kmem-harden时的后canary
+const CHUNK_TAIL: usize = if cfg!(feature = "kmem-harden") { size_of::<usize>() } else { 0 };
+
+//请求sz字节对应的chunk大小: 上舍入 整8字节, 加上头尾
+fn chunk_size(sz: usize) -> usize {
+	(align_val(sz, 3) + CHUNK_HDR + CHUNK_TAIL).max(MIN_CHUNK)
+}
+
+//数据指针 -> chunk头
+unsafe fn chunk_of(ptr: *mut u8) -> *mut AllocList {
+	ptr.sub(CHUNK_HDR) as *mut AllocList
+}
+
+fn kmalloc_from(sz: usize, align: usize, ra: usize) -> *mut u8 {
+	assert!(align.is_power_of_two());
+	//块头本身8字节对齐, 小于8的对齐自然满足
+	let order = align.max(size_of::<AllocList>()).trailing_zeros() as usize;
+	let size = chunk_size(sz);
+	//要对齐时最坏情况前面要切出一个空闲chunk
+	let need = if order > 3 { size + (1 << order) + MIN_CHUNK } else { size };
+	unsafe {
+		let irq = kmem_lock();
+		let mut c = find_fit(need);
+		if c.is_null() {
+			let pages = (align_val(size_of::<KmemRegion>() + need + size_of::<AllocList>(), 12) / PAGE_SIZE).max(KMEM_GROW_PAGES);
+			if grow(pages).is_null() {
+				//slab里空着的页先还回去再试一次
+				shrink_caches();
+				if grow(pages).is_null() {
+					kmem_unlock(irq);
+					return null_mut();
 				}
-				//移到AllocList结构后
-				return head.add(1) as *mut u8;
-			}else {
-				//当前不是空闲内存，移向下一个空闲空间
-				head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
 			}
+			c = find_fit(need);
+		}
+		let ret = take(c, size, order);
+		#[cfg(feature = "kmem-harden")]
+		harden::arm(chunk_of(ret), sz, ra);
+		let _ = ra;
+		kmem_unlock(irq);
+		ret
+	}
+}
+
+unsafe fn kmem_lock() -> usize {
+	let irq = irq_disable();
+	KMEM_LOCK.spin_lock();
+	irq
+}
+
+unsafe fn kmem_unlock(irq: usize) {
+	KMEM_LOCK.unlock();
+	irq_restore(irq);
+}
+
+//本级链表first-fit, 更高级的链表里任何一个都够大, 直接取第一个
+//找到的chunk已经从链表摘下
+unsafe fn find_fit(size: usize) -> *mut AllocList {
+	let class = size_class(size);
+	let mut f = FREE_LISTS[class];
+	while !f.is_null() {
+		if (*f).head.get_size() >= size {
+			free_list_remove(f as *mut AllocList);
+			return f as *mut AllocList;
+		}
+		f = (*f).next;
+	}
+	for k in class + 1..NUM_CLASSES {
+		let f = FREE_LISTS[k];
+		if !f.is_null() {
+			free_list_remove(f as *mut AllocList);
+			return f as *mut AllocList;
 		}
 	}
-	//内核空间的大块chunks不足
 	null_mut()
 }
 
+//从空闲chunk c中切出对齐的size字节; 前面的空隙和后面的剩余都还回空闲链表
+unsafe fn take(mut c: *mut AllocList, size: usize, order: usize) -> *mut u8 {
+	let mut total = (*c).get_size();
+	let mut data = align_val(c as usize + CHUNK_HDR, order);
+	while data - CHUNK_HDR - (c as usize) != 0 && data - CHUNK_HDR - (c as usize) < MIN_CHUNK {
+		data += 1 << order;
+	}
+	let gap = data - CHUNK_HDR - c as usize;
+	if gap > 0 {
+		make_free(c, gap);
+		c = (c as *mut u8).add(gap) as *mut AllocList;
+		(*c).flags_size = 0;
+		total -= gap;
+	}
+
+	(*c).set_taken();
+	let rem = total - size;
+	if rem >= MIN_CHUNK {
+		(*c).set_size(size);
+		//剩余的内核空间
+		let next = next_chunk(c);
+		(*next).flags_size = 0;
+		(*next).set_prev_taken();
+		make_free(next, rem);
+	}else{
+		//剩余空间太小，全部取走
+		(*c).set_size(total);
+		(*next_chunk(c)).set_prev_taken();
+	}
+	//移到chunk头后
+	(c as *mut u8).add(CHUNK_HDR)
+}
+
+//改变大小: 后面紧跟着的是空闲块就原地扩大, 否则重新分配再拷贝
+//ptr为空时等同kmalloc_aligned; 失败返回空指针, 原来的块不动
+#[inline(never)]
+pub fn krealloc(ptr: *mut u8, align: usize, sz: usize) -> *mut u8 {
+	let ra = return_address();
+	krealloc_from(ptr, align, sz, ra)
+}
+
+fn krealloc_from(ptr: *mut u8, align: usize, sz: usize, ra: usize) -> *mut u8 {
+	if ptr.is_null() {
+		return kmalloc_from(sz, align, ra);
+	}
+	unsafe {
+		let c = chunk_of(ptr);
+		assert!((*c).is_taken(), "krealloc of free chunk {:p}", ptr);
+		let size = chunk_size(sz);
+		let irq = kmem_lock();
+		#[cfg(feature = "kmem-harden")]
+		harden::check(c, "krealloc");
+		//原来的数据有多少字节
+		#[cfg(feature = "kmem-harden")]
+		let old = (*harden::zone(c)).size;
+		#[cfg(not(feature = "kmem-harden"))]
+		let old = (*c).get_size() - CHUNK_HDR;
+
+		let next = next_chunk(c);
+		if (*c).get_size() < size && (*next).is_free() && (*c).get_size() + (*next).get_size() >= size {
+			free_list_remove(next);
+			let merged = (*c).get_size() + (*next).get_size();
+			(*c).set_size(merged);
+			(*next_chunk(c)).set_prev_taken();
+		}
+
+		if (*c).get_size() >= size {
+			//多出来的部分切成一个已分配chunk再释放, 顺便和后面合并
+			let rem = (*c).get_size() - size;
+			if rem >= MIN_CHUNK {
+				(*c).set_size(size);
+				let rest = next_chunk(c);
+				(*rest).flags_size = 0;
+				(*rest).set_size(rem);
+				(*rest).set_taken();
+				(*rest).set_prev_taken();
+				kfree_locked(rest);
+			}
+			#[cfg(feature = "kmem-harden")]
+			harden::arm(c, sz, ra);
+			kmem_unlock(irq);
+			return ptr;
+		}
+		kmem_unlock(irq);
+
+		//ptr还是我们的, 放开锁再分配、拷贝
+		let new = kmalloc_from(sz, align, ra);
+		if !new.is_null() {
+			core::ptr::copy_nonoverlapping(ptr, new, old.min(sz));
+			kfree(ptr);
+		}
+		new
+	}
+}
+
+//和前后相邻的空闲chunk合并, O(1)
 pub fn kfree(ptr: *mut u8) {
 	unsafe {
-		if !ptr.is_null() {
-			//取出前置的AllocList结构
-			let p = (ptr as *mut AllocList).offset(-1);
-			if (*p).is_taken() {
-				(*p).set_free();
+		if ptr.is_null() {
+			return;
+		}
+		//取出前置的AllocList结构
+		let c = chunk_of(ptr);
+		let irq = kmem_lock();
+		#[cfg(feature = "kmem-harden")]
+		harden::check(c, "kfree");
+		if (*c).is_taken() {
+			kfree_locked(c);
+		}
+		kmem_unlock(irq);
+	}
+}
+
+//c是已分配chunk的头, 调用者持有KMEM_LOCK
+unsafe fn kfree_locked(mut c: *mut AllocList) {
+	let mut size = (*c).get_size();
+	let next = next_chunk(c);
+	if (*next).is_free() {
+		free_list_remove(next);
+		size += (*next).get_size();
+	}
+	if !(*c).is_prev_taken() {
+		c = prev_chunk(c);
+		free_list_remove(c);
+		size += (*c).get_size();
+	}
+	make_free(c, size);
+
+	//后面就是结束标记时, 看看是不是整个扩展region都空了, 空了就还给页分配器
+	if (*next_chunk(c)).get_size() == 0 {
+		let r = (c as *mut KmemRegion).offset(-1);
+		if r != KMEM_REGIONS && (*c).is_prev_taken() && is_region(r) {
+			release(r);
+		}
+	}
+}
+
+unsafe fn is_region(r: *mut KmemRegion) -> bool {
+	let mut p = KMEM_REGIONS;
+	while !p.is_null() && p != r {
+		p = (*p).next;
+	}
+	!p.is_null()
+}
+
+unsafe fn release(r: *mut KmemRegion) {
+	free_list_remove((*r).head());
+	let mut prev = KMEM_REGIONS;
+	while (*prev).next != r {
+		prev = (*prev).next;
+	}
+	(*prev).next = (*r).next;
+	KMEM_ALLOC -= (*r).pages;
+	dealloc(r as *mut u8);
+}
+
+//把空了的扩展region都还给页分配器, 返回释放的页数
+pub fn trim() -> usize {
+	let mut freed = 0;
+	unsafe {
+		let irq = kmem_lock();
+		let mut r = (*KMEM_REGIONS).next;
+		while !r.is_null() {
+			let next = (*r).next;
+			if (*r).is_empty() {
+				freed += (*r).pages;
+				release(r);
 			}
-			//合并碎片
-			coalesce();
+			r = next;
 		}
+		kmem_unlock(irq);
+	}
+	freed
+}
+
+//print kmem table
+pub fn print_table() {
+	unsafe {
+		let irq = kmem_lock();
+		let mut r = KMEM_REGIONS;
+		println!("~~~~~KMEM Table~~~~~");
+		while !r.is_null() {
+			println!("region {:p}: {} pages", r, (*r).pages);
+			let mut head = (*r).head();
+			let tail = (*r).tail();
+			while head < tail {
+				println!("{:p}: Length = {:<10} Taken = {}", head, (*head).get_size(), (*head).is_taken());
+				head = next_chunk(head);
+			}
+			r = (*r).next;
+		}
+		for k in 0..NUM_CLASSES {
+			let mut n = 0;
+			let mut f = FREE_LISTS[k];
+			while !f.is_null() {
+				n += 1;
+				f = (*f).next;
+			}
+			if n > 0 {
+				println!("free list {:>2} (>= {:>6} bytes): {}", k, MIN_CHUNK << k, n);
+			}
+		}
+		println!("~~~~~~~~~~~~~~~~~~~~");
+		kmem_unlock(irq);
+	}
+}
+
+//检查整个堆: chunk大小、空闲chunk的尾标记、相邻的PrevTaken位, kmem-harden时还有canary
+//返回发现的问题个数; 大小坏了的region后面就没法走了, 直接跳到下一个region
+pub fn verify() -> usize {
+	unsafe {
+		let irq = kmem_lock();
+		let bad = verify_locked();
+		kmem_unlock(irq);
+		bad
 	}
 }
 
-//把小块合并成大块
-pub fn coalesce() {
+//拿不到锁就跳过, 给定时器中断用
+pub fn try_verify() -> Option<usize> {
 	unsafe {
-		let mut head = KMEM_HEAD;
-		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
+		let irq = irq_disable();
+		if !KMEM_LOCK.try_lock() {
+			irq_restore(irq);
+			return None;
+		}
+		let bad = verify_locked();
+		kmem_unlock(irq);
+		Some(bad)
+	}
+}
+
+unsafe fn verify_locked() -> usize {
+	let mut bad = 0;
+	let mut r = KMEM_REGIONS;
+	while !r.is_null() {
+		let mut prev: *mut AllocList = null_mut();
+		let mut head = (*r).head();
+		let tail = (*r).tail();
 		while head < tail {
-			let next = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
-			if (*head).get_size() == 0{
-				//可能堆坏了，double free或咋了,next指针无法往前移，导致无限循环
+			let size = (*head).get_size();
+			if size < MIN_CHUNK || size % size_of::<usize>() != 0 || (head as *mut u8).add(size) > tail as *mut u8 {
+				report_chunk(head, prev, "bad chunk size");
+				bad += 1;
 				break;
-			}else if next >= tail {
+			}
+			let next = next_chunk(head);
+			if (*head).is_free() {
+				if *(next as *mut usize).offset(-1) != size {
+					report_chunk(head, prev, "free chunk footer does not match its size");
+					bad += 1;
+				}
+				if next < tail && (*next).is_free() {
+					report_chunk(head, prev, "two adjacent free chunks");
+					bad += 1;
+				}
+				if (*next).is_prev_taken() {
+					report_chunk(next, head, "PrevTaken set after a free chunk");
+					bad += 1;
+				}
+			}else{
+				if !(*next).is_prev_taken() {
+					report_chunk(next, head, "PrevTaken clear after a taken chunk");
+					bad += 1;
+				}
+				#[cfg(feature = "kmem-harden")]
+				{
+					if let Some(what) = harden::corrupted(head) {
+						report_chunk(head, prev, what);
+						bad += 1;
+					}
+				}
+			}
+			prev = head;
+			head = next;
+		}
+		r = (*r).next;
+	}
+	bad
+}
+
+//prev是前一个相邻的chunk, 往往就是写越界的那个
+unsafe fn report_chunk(c: *mut AllocList, prev: *mut AllocList, what: &str) {
+	println!("kmem: corrupted chunk {:p}: {} (header 0x{:x})", c, what, (*c).flags_size);
+	#[cfg(feature = "kmem-harden")]
+	{
+		if (*c).is_taken() {
+			println!("    owner ra 0x{:x}, {} bytes", (*harden::zone(c)).owner, (*harden::zone(c)).size);
+		}
+		if !prev.is_null() && (*prev).is_taken() {
+			println!("    previous chunk {:p} owned by ra 0x{:x} may have overrun it", prev, (*harden::zone(prev)).owner);
+		}
+	}
+	#[cfg(not(feature = "kmem-harden"))]
+	{
+		if !prev.is_null() {
+			println!("    previous chunk {:p} (header 0x{:x})", prev, (*prev).flags_size);
+		}
+	}
+}
+
+//kmem-harden: 每个已分配chunk的数据前后放canary, 头里再记下调用者和请求的大小
+//kfree/krealloc时检查, 定时器中断里定期把整个堆检查一遍
+#[cfg(feature = "kmem-harden")]
+mod harden {
+	use super::{align_val, next_chunk, report_chunk, AllocList, CHUNK_HDR};
+	use core::ptr::null_mut;
+
+	pub const CANARY_FRONT: usize = 0xc0de_5afe_c0de_5afe;
+	pub const CANARY_REAR: usize = 0x5afe_c0de_5afe_c0de;
+
+	//紧跟在AllocList后面
+	#[repr(C)]
+	pub struct RedZone {
+		pub owner:  usize,
+		pub size:   usize,
+		pub canary: usize,
+	}
+
+	pub unsafe fn zone(c: *mut AllocList) -> *mut RedZone {
+		c.add(1) as *mut RedZone
+	}
+
+	unsafe fn rear(c: *mut AllocList) -> *mut usize {
+		(c as *mut u8).add(CHUNK_HDR + align_val((*zone(c)).size, 3)) as *mut usize
+	}
+
+	pub unsafe fn arm(c: *mut AllocList, size: usize, owner: usize) {
+		let z = zone(c);
+		(*z).owner = owner;
+		(*z).size = size;
+		(*z).canary = CANARY_FRONT;
+		*rear(c) = CANARY_REAR;
+	}
+
+	//坏了返回哪里坏了
+	pub unsafe fn corrupted(c: *mut AllocList) -> Option<&'static str> {
+		let z = zone(c);
+		if (*z).canary != CANARY_FRONT {
+			return Some("front canary overwritten");
+		}
+		if rear(c).add(1) as *mut AllocList > next_chunk(c) {
+			return Some("recorded size does not fit in the chunk");
+		}
+		if *rear(c) != CANARY_REAR {
+			return Some("rear canary overwritten (buffer overrun)");
+		}
+		None
+	}
+
+	//释放一个不是已分配chunk的指针也算
+	pub unsafe fn check(c: *mut AllocList, op: &str) {
+		let what = if (*c).is_taken() { corrupted(c) } else { Some("not an allocated chunk (double free?)") };
+		if let Some(what) = what {
+			println!("kmem: {} of {:p}", op, (c as *mut u8).add(CHUNK_HDR));
+			report_chunk(c, null_mut(), what);
+			panic!("kernel heap corruption");
+		}
+	}
+}
+
+//slab分配器: 固定大小对象的cache, 每个slab是从页分配器拿的一块连续页
+//slab开头放Slab结构, 后面是一个个对象; 空闲对象的第一个字存下一个空闲对象的地址
+//每个hart在cache前面有一个小的对象缓存(magazine), 空了或满了才拿cache的锁
+struct Slab {
+	next:  *mut Slab,
+	prev:  *mut Slab,
+	cache: *mut KmemCache,
+	free:  *mut usize,
+	inuse: usize,
+}
+
+pub struct KmemCache {
+	name:    &'static str,
+	size:    usize,
+	align:   usize,
+	ctor:    Option<fn(*mut u8)>,
+	pages:   usize, //每个slab的页数, 0表示还没初始化
+	objs:    usize, //每个slab的对象数
+	offset:  usize, //第一个对象相对slab开头的偏移
+	partial: *mut Slab,
+	full:    *mut Slab,
+	empty:   *mut Slab,
+	next:    *mut KmemCache, //所有cache串成链表, print_caches()用
+	lock:    Mutex, //保护slab链表和统计
+	cpu:     [Magazine; MAX_HARTS],
+	//统计, 按slab层算: 各hart缓存着的对象也算active
+	slabs:   usize,
+	active:  usize,
+	peak:    usize,
+	allocs:  usize,
+	frees:   usize,
+}
+
+#[derive(Clone, Copy)]
+struct Magazine {
+	count: usize,
+	objs:  [*mut u8; MAG_SIZE],
+}
+
+const MAG_SIZE: usize = 16;
+//空了一次从slab拿这么多, 满了一次还这么多
+const MAG_BATCH: usize = 8;
+
+//已初始化的cache链表
+static mut KMEM_CACHES: *mut KmemCache = null_mut();
+static mut KMEM_CACHES_LOCK: Mutex = Mutex::new();
+//每个slab至少放这么多对象, 大对象用多页的slab
+const SLAB_MIN_OBJS: usize = 8;
+const SLAB_MAX_PAGES: usize = 8;
+
+unsafe fn slab_push(list: &mut *mut Slab, s: *mut Slab) {
+	(*s).prev = null_mut();
+	(*s).next = *list;
+	if !(*list).is_null() {
+		(**list).prev = s;
+	}
+	*list = s;
+}
+
+unsafe fn slab_remove(list: &mut *mut Slab, s: *mut Slab) {
+	if (*s).prev.is_null() {
+		*list = (*s).next;
+	}else{
+		(*(*s).prev).next = (*s).next;
+	}
+	if !(*s).next.is_null() {
+		(*(*s).next).prev = (*s).prev;
+	}
+	(*s).next = null_mut();
+	(*s).prev = null_mut();
+}
+
+impl KmemCache {
+	//align必须是2的幂; ctor在对象每次分配、清零之后调用
+	pub const fn new(name: &'static str, size: usize, align: usize, ctor: Option<fn(*mut u8)>) -> Self {
+		KmemCache { name, size, align, ctor,
+		            pages: 0, objs: 0, offset: 0,
+		            partial: null_mut(), full: null_mut(), empty: null_mut(), next: null_mut(),
+		            lock: Mutex::new(), cpu: [Magazine { count: 0, objs: [null_mut(); MAG_SIZE] }; MAX_HARTS],
+		            slabs: 0, active: 0, peak: 0, allocs: 0, frees: 0,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		self.name
+	}
+
+	//第一次使用时计算布局并登记到cache链表, 调用者持有self.lock
+	unsafe fn setup(&mut self) {
+		if self.pages != 0 {
+			return;
+		}
+		assert!(self.align.is_power_of_two() && self.align <= PAGE_SIZE);
+		//空闲对象要能放下一个指针
+		let order = self.align.max(size_of::<usize>()).trailing_zeros() as usize;
+		self.size = align_val(self.size.max(size_of::<usize>()), order);
+		self.offset = align_val(size_of::<Slab>(), order);
+		self.pages = 1;
+		while self.pages < SLAB_MAX_PAGES && (self.pages * PAGE_SIZE - self.offset) / self.size < SLAB_MIN_OBJS {
+			self.pages *= 2;
+		}
+		self.objs = (self.pages * PAGE_SIZE - self.offset) / self.size;
+		assert!(self.objs > 0, "kmem cache {}: object of {} bytes is too large", self.name, self.size);
+
+		KMEM_CACHES_LOCK.spin_lock();
+		self.next = KMEM_CACHES;
+		KMEM_CACHES = self as *mut KmemCache;
+		KMEM_CACHES_LOCK.unlock();
+	}
+
+	//从页分配器拿一个新的slab, 串好空闲对象链表
+	unsafe fn grow(&mut self) -> *mut Slab {
+		let s = alloc(self.pages) as *mut Slab;
+		if s.is_null() {
+			return null_mut();
+		}
+		(*s).next = null_mut();
+		(*s).prev = null_mut();
+		(*s).cache = self as *mut KmemCache;
+		(*s).inuse = 0;
+		(*s).free = null_mut();
+		for i in (0..self.objs).rev() {
+			let obj = (s as *mut u8).add(self.offset + i * self.size) as *mut usize;
+			*obj = (*s).free as usize;
+			(*s).free = obj;
+		}
+		self.slabs += 1;
+		s
+	}
+
+	//从slab里拿一个对象, 调用者持有self.lock
+	unsafe fn alloc_locked(&mut self) -> *mut u8 {
+		self.setup();
+		let s = if !self.partial.is_null() {
+			self.partial
+		}else if !self.empty.is_null() {
+			let s = self.empty;
+			slab_remove(&mut self.empty, s);
+			slab_push(&mut self.partial, s);
+			s
+		}else{
+			let s = self.grow();
+			if s.is_null() {
+				return null_mut();
+			}
+			slab_push(&mut self.partial, s);
+			s
+		};
+
+		let obj = (*s).free;
+		(*s).free = *obj as *mut usize;
+		(*s).inuse += 1;
+		if (*s).inuse == self.objs {
+			slab_remove(&mut self.partial, s);
+			slab_push(&mut self.full, s);
+		}
+
+		self.allocs += 1;
+		self.active += 1;
+		if self.active > self.peak {
+			self.peak = self.active;
+		}
+		obj as *mut u8
+	}
+
+	//对象还回它的slab; 空的slab只留一个备用, 多的还给页分配器
+	//调用者持有self.lock
+	unsafe fn free_locked(&mut self, ptr: *mut u8) {
+		let s = block_of(ptr as usize).unwrap() as *mut Slab;
+		assert!((*s).inuse > 0, "kmem cache {}: double free of {:p}", self.name, ptr);
+
+		if (*s).inuse == self.objs {
+			slab_remove(&mut self.full, s);
+			slab_push(&mut self.partial, s);
+		}
+		let obj = ptr as *mut usize;
+		*obj = (*s).free as usize;
+		(*s).free = obj;
+		(*s).inuse -= 1;
+
+		self.frees += 1;
+		self.active -= 1;
+
+		if (*s).inuse == 0 {
+			slab_remove(&mut self.partial, s);
+			if self.empty.is_null() {
+				slab_push(&mut self.empty, s);
+			}else{
+				self.slabs -= 1;
+				dealloc(s as *mut u8);
+			}
+		}
+	}
+
+	//分配一个清零的对象, 再调用构造函数
+	pub fn alloc(&mut self) -> *mut u8 {
+		unsafe {
+			let irq = irq_disable();
+			let hart = hart_id();
+			if self.cpu[hart].count == 0 {
+				//先拿到局部数组里, alloc_locked()要借整个self
+				let mut batch = [null_mut(); MAG_BATCH];
+				let mut n = 0;
+				self.lock.spin_lock();
+				while n < MAG_BATCH {
+					let obj = self.alloc_locked();
+					if obj.is_null() {
+						break;
+					}
+					batch[n] = obj;
+					n += 1;
+				}
+				self.lock.unlock();
+				let mag = &mut self.cpu[hart];
+				mag.objs[..n].copy_from_slice(&batch[..n]);
+				mag.count = n;
+			}
+			let mag = &mut self.cpu[hart];
+			let mut obj = null_mut();
+			if mag.count > 0 {
+				mag.count -= 1;
+				obj = mag.objs[mag.count];
+			}
+			irq_restore(irq);
+			if obj.is_null() {
+				return obj;
+			}
+
+			for i in 0..self.size {
+				*obj.add(i) = 0;
+			}
+			if let Some(ctor) = self.ctor {
+				ctor(obj);
+			}
+			obj
+		}
+	}
+
+	//还回对象, 先放进本hart的缓存
+	pub fn free(&mut self, ptr: *mut u8) {
+		if ptr.is_null() {
+			return;
+		}
+		unsafe {
+			let s = match block_of(ptr as usize) {
+				Some(addr) => addr as *mut Slab,
+				None => panic!("kmem cache {}: free of {:p}, not a slab object", self.name, ptr),
+			};
+			assert!((*s).cache == self as *mut KmemCache, "kmem cache {}: free of {:p} from another cache", self.name, ptr);
+			let off = ptr as usize - s as usize;
+			assert!(off >= self.offset && (off - self.offset) % self.size == 0, "kmem cache {}: free of misaligned object {:p}", self.name, ptr);
+
+			let irq = irq_disable();
+			let hart = hart_id();
+			if self.cpu[hart].count == MAG_SIZE {
+				//先从缓存里拿出来, free_locked()要借整个self
+				let mag = &mut self.cpu[hart];
+				mag.count -= MAG_BATCH;
+				let mut batch = [null_mut(); MAG_BATCH];
+				batch.copy_from_slice(&mag.objs[mag.count..mag.count + MAG_BATCH]);
+				self.lock.spin_lock();
+				for obj in batch.iter() {
+					self.free_locked(*obj);
+				}
+				self.lock.unlock();
+			}
+			let mag = &mut self.cpu[hart];
+			mag.objs[mag.count] = ptr;
+			mag.count += 1;
+			irq_restore(irq);
+		}
+	}
+
+	//把本hart缓存的对象和所有空的slab还给页分配器, 返回释放的页数
+	//别的hart缓存着的对象不动, 它们所在的slab也就空不了
+	pub fn shrink(&mut self) -> usize {
+		let mut freed = 0;
+		unsafe {
+			let irq = irq_disable();
+			self.lock.spin_lock();
+			let mag = self.cpu[hart_id()];
+			self.cpu[hart_id()].count = 0;
+			for obj in mag.objs[..mag.count].iter() {
+				self.free_locked(*obj);
+			}
+			while !self.empty.is_null() {
+				let s = self.empty;
+				slab_remove(&mut self.empty, s);
+				dealloc(s as *mut u8);
+				self.slabs -= 1;
+				freed += self.pages;
+			}
+			self.lock.unlock();
+			irq_restore(irq);
+		}
+		freed
+	}
+}
+
+//通用的各种大小的cache, GlobalAlloc的小对象都走这里
+//对象只按16字节对齐: 按自己的大小对齐的话slab头要占掉一整个对象(kmalloc-2048每个slab丢2048字节)
+const KMALLOC_MIN: usize = 16;
+const KMALLOC_MAX: usize = 2048;
+const KMALLOC_ALIGN: usize = 16;
+static mut KMALLOC_CACHES: [KmemCache; 8] = [
+	KmemCache::new("kmalloc-16", 16, KMALLOC_ALIGN, None),
+	KmemCache::new("kmalloc-32", 32, KMALLOC_ALIGN, None),
+	KmemCache::new("kmalloc-64", 64, KMALLOC_ALIGN, None),
+	KmemCache::new("kmalloc-128", 128, KMALLOC_ALIGN, None),
+	KmemCache::new("kmalloc-256", 256, KMALLOC_ALIGN, None),
+	KmemCache::new("kmalloc-512", 512, KMALLOC_ALIGN, None),
+	KmemCache::new("kmalloc-1024", 1024, KMALLOC_ALIGN, None),
+	KmemCache::new("kmalloc-2048", 2048, KMALLOC_ALIGN, None),
+];
+
+//按大小选一个通用cache, 太大或者要求对齐超过16字节就返回None(走first-fit链表)
+//kmem-harden时都走链表, 每个分配都有红区
+fn kmalloc_cache(sz: usize, align: usize) -> Option<&'static mut KmemCache> {
+	if cfg!(feature = "kmem-harden") || align > KMALLOC_ALIGN {
+		return None;
+	}
+	let want = sz.max(align).max(KMALLOC_MIN);
+	if want > KMALLOC_MAX {
+		return None;
+	}
+	let idx = (want.next_power_of_two() / KMALLOC_MIN).trailing_zeros() as usize;
+	unsafe { Some(&mut KMALLOC_CACHES[idx]) }
+}
+
+//所有cache都收缩一遍, 返回释放的页数
+pub fn shrink_caches() -> usize {
+	let mut freed = 0;
+	unsafe {
+		//cache只会加不会删, 拿到表头以后不用一直持锁
+		KMEM_CACHES_LOCK.spin_lock();
+		let mut c = KMEM_CACHES;
+		KMEM_CACHES_LOCK.unlock();
+		while !c.is_null() {
+			freed += (*c).shrink();
+			c = (*c).next;
+		}
+	}
+	freed
+}
+
+pub fn print_caches() {
+	unsafe {
+		println!("~~~~~KMEM Caches~~~~~");
+		println!("{:<14} {:>6} {:>6} {:>6} {:>6} {:>6} {:>8} {:>8}", "name", "size", "active", "total", "peak", "slabs", "allocs", "frees");
+		KMEM_CACHES_LOCK.spin_lock();
+		let mut c = KMEM_CACHES;
+		KMEM_CACHES_LOCK.unlock();
+		while !c.is_null() {
+			println!("{:<14} {:>6} {:>6} {:>6} {:>6} {:>6} {:>8} {:>8}",
+			         (*c).name, (*c).size, (*c).active, (*c).slabs * (*c).objs, (*c).peak, (*c).slabs, (*c).allocs, (*c).frees);
+			c = (*c).next;
+		}
+		println!("~~~~~~~~~~~~~~~~~~~~~");
+	}
+}
+
+//堆统计, 记的是GlobalAlloc的分配(包括走slab的小对象), 按请求的字节数算
+//次数和调用点每个hart各记各的, 打印时再加起来; 在用字节数和峰值是全局的原子量
+#[derive(Clone, Copy)]
+struct HeapStats {
+	allocs: usize,
+	frees:  usize,
+	failed: usize,
+}
+
+static HEAP_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static HEAP_PEAK: AtomicUsize = AtomicUsize::new(0);
+static mut HEAP_STATS: [HeapStats; MAX_HARTS] = [HeapStats { allocs: 0, frees: 0, failed: 0 }; MAX_HARTS];
+
+//按调用点(返回地址)统计分配次数和字节数, 表满了就记到最后一项(ra = 0)
+#[derive(Clone, Copy)]
+struct AllocSite {
+	ra:    usize,
+	count: usize,
+	bytes: usize,
+}
+
+const MAX_SITES: usize = 64;
+static mut ALLOC_SITES: [[AllocSite; MAX_SITES]; MAX_HARTS] = [[AllocSite { ra: 0, count: 0, bytes: 0 }; MAX_SITES]; MAX_HARTS];
+
+//泄漏检查: leak_begin()和leak_end()之间分配、到最后还没释放的
+#[derive(Clone, Copy)]
+struct LiveAlloc {
+	ptr:  usize,
+	size: usize,
+	ra:   usize,
+}
+
+const MAX_TRACKED: usize = 512;
+static mut LEAK_TRACKING: bool = false;
+static mut LEAK_LOCK: Mutex = Mutex::new();
+static mut LEAK_LIVE: [LiveAlloc; MAX_TRACKED] = [LiveAlloc { ptr: 0, size: 0, ra: 0 }; MAX_TRACKED];
+static mut LEAK_COUNT: usize = 0;
+//表满了没记下来的分配个数
+static mut LEAK_DROPPED: usize = 0;
+
+//ra相同的记到同一项, 表满了记到最后一项
+fn site_add(sites: &mut [AllocSite; MAX_SITES], ra: usize, count: usize, bytes: usize) {
+	let mut idx = MAX_SITES - 1;
+	for i in 0..MAX_SITES - 1 {
+		if sites[i].ra == ra || sites[i].count == 0 {
+			idx = i;
+			break;
+		}
+	}
+	let site = &mut sites[idx];
+	site.ra = if idx == MAX_SITES - 1 { 0 } else { ra };
+	site.count += count;
+	site.bytes += bytes;
+}
+
+unsafe fn account_alloc(ptr: *mut u8, size: usize, ra: usize) {
+	let irq = irq_disable();
+	let hart = hart_id();
+	if ptr.is_null() {
+		HEAP_STATS[hart].failed += 1;
+		irq_restore(irq);
+		return;
+	}
+	HEAP_STATS[hart].allocs += 1;
+	site_add(&mut ALLOC_SITES[hart], ra, 1, size);
+	irq_restore(irq);
+
+	let in_use = HEAP_IN_USE.fetch_add(size, Ordering::Relaxed) + size;
+	let mut peak = HEAP_PEAK.load(Ordering::Relaxed);
+	while in_use > peak {
+		match HEAP_PEAK.compare_exchange(peak, in_use, Ordering::Relaxed, Ordering::Relaxed) {
+			Ok(_) => break,
+			Err(p) => peak = p,
+		}
+	}
+
+	if LEAK_TRACKING {
+		let irq = irq_disable();
+		LEAK_LOCK.spin_lock();
+		if LEAK_COUNT < MAX_TRACKED {
+			LEAK_LIVE[LEAK_COUNT] = LiveAlloc { ptr: ptr as usize, size, ra };
+			LEAK_COUNT += 1;
+		}else{
+			LEAK_DROPPED += 1;
+		}
+		LEAK_LOCK.unlock();
+		irq_restore(irq);
+	}
+}
+
+unsafe fn account_free(ptr: *mut u8, size: usize) {
+	let irq = irq_disable();
+	HEAP_STATS[hart_id()].frees += 1;
+	HEAP_IN_USE.fetch_sub(size, Ordering::Relaxed);
+
+	if LEAK_TRACKING {
+		LEAK_LOCK.spin_lock();
+		for i in 0..LEAK_COUNT {
+			if LEAK_LIVE[i].ptr == ptr as usize {
+				LEAK_COUNT -= 1;
+				LEAK_LIVE[i] = LEAK_LIVE[LEAK_COUNT];
 				break;
-			}else if (*head).is_free() && (*next).is_free() {
-				(*head).set_size((*head).get_size() + (*next).get_size());
 			}
+		}
+		LEAK_LOCK.unlock();
+	}
+	irq_restore(irq);
+}
 
-			head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+//开始记录分配, 和leak_end()成对使用, 例如包住进程的创建和销毁
+pub fn leak_begin() {
+	unsafe {
+		LEAK_LOCK.spin_lock();
+		LEAK_COUNT = 0;
+		LEAK_DROPPED = 0;
+		LEAK_TRACKING = true;
+		LEAK_LOCK.unlock();
+	}
+}
+
+//停止记录, 打印leak_begin()以来分配了却还没释放的, 返回个数
+pub fn leak_end(name: &str) -> usize {
+	unsafe {
+		LEAK_LOCK.spin_lock();
+		LEAK_TRACKING = false;
+		LEAK_LOCK.unlock();
+		if LEAK_COUNT == 0 {
+			println!("leak check {}: no leaks", name);
+		}else{
+			println!("leak check {}: {} allocation(s) survived", name, LEAK_COUNT);
+			for i in 0..LEAK_COUNT {
+				let l = &LEAK_LIVE[i];
+				println!("    0x{:x}: {:>6} bytes from ra 0x{:x}", l.ptr, l.size, l.ra);
+			}
+		}
+		if LEAK_DROPPED > 0 {
+			println!("    ({} allocation(s) not tracked, table full)", LEAK_DROPPED);
 		}
+		LEAK_COUNT
 	}
 }
 
-//print kmem table
-pub fn print_table() {
+pub fn print_stats() {
 	unsafe {
-		let mut head = KMEM_HEAD;
-		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
-		println!("~~~~~KMEM Table~~~~~");
-		while head < tail {
-			println!("{:p}: Length = {:<10} Taken = {}", head, (*head).get_size(), (*head).is_taken());
-			head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+		//扫描堆: 已用、空闲、最大空闲块
+		let mut used = 0;
+		let mut free = 0;
+		let mut largest = 0;
+		let irq = kmem_lock();
+		let mut r = KMEM_REGIONS;
+		while !r.is_null() {
+			let mut head = (*r).head();
+			let tail = (*r).tail();
+			while head < tail {
+				let size = (*head).get_size();
+				if (*head).is_taken() {
+					used += size;
+				}else{
+					free += size;
+					largest = largest.max(size);
+				}
+				head = next_chunk(head);
+			}
+			r = (*r).next;
+		}
+		let heap_pages = KMEM_ALLOC;
+		kmem_unlock(irq);
+		//碎片率: 空闲空间里不在最大空闲块中的比例
+		let frag = if free == 0 { 0 } else { 100 - largest * 100 / free };
+
+		let mut slab_used = 0;
+		let mut slab_pages = 0;
+		KMEM_CACHES_LOCK.spin_lock();
+		let mut c = KMEM_CACHES;
+		KMEM_CACHES_LOCK.unlock();
+		while !c.is_null() {
+			slab_used += (*c).active * (*c).size;
+			slab_pages += (*c).slabs * (*c).pages;
+			c = (*c).next;
+		}
+
+		//各hart的计数加起来
+		let mut total = HeapStats { allocs: 0, frees: 0, failed: 0 };
+		let mut sites = [AllocSite { ra: 0, count: 0, bytes: 0 }; MAX_SITES];
+		for hart in 0..MAX_HARTS {
+			total.allocs += HEAP_STATS[hart].allocs;
+			total.frees += HEAP_STATS[hart].frees;
+			total.failed += HEAP_STATS[hart].failed;
+			for site in ALLOC_SITES[hart].iter() {
+				if site.count > 0 {
+					site_add(&mut sites, site.ra, site.count, site.bytes);
+				}
+			}
+		}
+
+		println!("~~~~~KMEM Stats~~~~~");
+		println!("in use:       {} bytes (peak {})", HEAP_IN_USE.load(Ordering::Relaxed), HEAP_PEAK.load(Ordering::Relaxed));
+		println!("allocs/frees: {}/{} ({} failed)", total.allocs, total.frees, total.failed);
+		println!("heap:         {} pages, {} bytes used, {} bytes free", heap_pages, used, free);
+		println!("largest free: {} bytes, fragmentation {}%", largest, frag);
+		println!("slabs:        {} pages, {} bytes in objects", slab_pages, slab_used);
+		println!("call sites:");
+		let mut printed = [false; MAX_SITES];
+		loop {
+			//按分配次数从多到少
+			let mut best = MAX_SITES;
+			for i in 0..MAX_SITES {
+				if !printed[i] && sites[i].count > 0 && (best == MAX_SITES || sites[i].count > sites[best].count) {
+					best = i;
+				}
+			}
+			if best == MAX_SITES {
+				break;
+			}
+			printed[best] = true;
+			let site = &sites[best];
+			if site.ra == 0 {
+				println!("    (other)            {:>8} allocs {:>10} bytes", site.count, site.bytes);
+			}else{
+				print!("    ra 0x{:<12x} {:>8} allocs {:>10} bytes", site.ra, site.count, site.bytes);
+				match backtrace::resolve(site.ra.wrapping_sub(1)) {
+					Some((name, off)) => println!("  {}+{:#x}", name, off + 1),
+					None => println!(),
+				}
+			}
 		}
 		println!("~~~~~~~~~~~~~~~~~~~~");
 	}
@@ -175,13 +1277,75 @@ use core::alloc::{GlobalAlloc, Layout};
 
 struct OsGlobalAlloc;
 
+impl OsGlobalAlloc {
+	unsafe fn raw_alloc(&self, layout: Layout, ra: usize) -> *mut u8 {
+		match kmalloc_cache(layout.size(), layout.align()) {
+			Some(cache) => cache.alloc(),
+			None => kzmalloc_from(layout.size(), layout.align(), ra),
+		}
+	}
+
+	unsafe fn raw_dealloc(&self, ptr: *mut u8, layout: Layout) {
+		match kmalloc_cache(layout.size(), layout.align()) {
+			Some(cache) => cache.free(ptr),
+			None => kfree(ptr),
+		}
+	}
+}
+
+//GlobalAlloc的直接调用者总是编译器生成的__rust_alloc/__rg_alloc, 中间还有alloc/core里的Box、RawVec等;
+//沿fp链跳过这些帧才是真正分配的内核代码. 没有符号表时退回直接调用者
+const ALLOC_FRAMES: &[&str] = &["<sos::kmem::OsGlobalAlloc", "__rust_", "__rg_", "alloc::", "<alloc::", "core::", "<core::"];
+
+//走fp链、查.ksyms每次分配都做太慢, 只在查泄漏时做; 平时记直接调用者, print_stats()时再解析
+#[inline(always)]
+fn alloc_site(shim: usize) -> usize {
+	if unsafe { LEAK_TRACKING } {
+		backtrace::caller_outside(ALLOC_FRAMES).unwrap_or(shim)
+	}else{
+		shim
+	}
+}
+
+//统计调用点要读返回地址, 所以不能内联
 unsafe impl GlobalAlloc for OsGlobalAlloc {
+	#[inline(never)]
 	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-		kzmalloc(layout.size())
+		let ra = alloc_site(return_address());
+		let ret = self.raw_alloc(layout, ra);
+		account_alloc(ret, layout.size(), ra);
+		ret
 	}
 
-	unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-		kfree(ptr);
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		account_free(ptr, layout.size());
+		self.raw_dealloc(ptr, layout);
+	}
+
+	#[inline(never)]
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		let ra = alloc_site(return_address());
+		let old = kmalloc_cache(layout.size(), layout.align()).map(|c| c as *mut KmemCache);
+		let new = kmalloc_cache(new_size, layout.align()).map(|c| c as *mut KmemCache);
+		let ret = match (old, new) {
+			//同一个cache的对象放得下
+			(Some(a), Some(b)) if a == b => ptr,
+			(None, None) => krealloc_from(ptr, layout.align(), new_size, ra),
+			_ => {
+				let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+				let ret = self.raw_alloc(new_layout, ra);
+				if !ret.is_null() {
+					core::ptr::copy_nonoverlapping(ptr, ret, layout.size().min(new_size));
+					self.raw_dealloc(ptr, layout);
+				}
+				ret
+			},
+		};
+		if !ret.is_null() {
+			account_free(ptr, layout.size());
+		}
+		account_alloc(ret, new_size, ra);
+		ret
 	}
 }
 
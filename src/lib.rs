@@ -12,7 +12,10 @@ extern crate alloc;
 use crate::cpu::mstatus_read;
 use alloc::prelude::v1::*;
 
-use crate::user::syscall;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+//hart 0把分配器和内核页表都准备好后置位, 其他hart才能开始初始化
+static KERNEL_READY: AtomicBool = AtomicBool::new(false);
 
 #[macro_export]
 macro_rules! print
@@ -68,6 +71,7 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 	}else{
 		println!("no information available.");
 	}
+	backtrace::print_backtrace();
 	abort();
 }
 
@@ -102,23 +106,30 @@ extern "C" {
 }
 
 //指定内存范围进行恒等映射，虚拟地址 = 物理地址
+//对齐的部分会自动用2M/1G大页
 pub fn id_map_range(root: &mut page::Table, start: usize, end: usize, bits: i64)
 {
-	let mut memaddr = start & !(page::PAGE_SIZE -1);
+	let memaddr = start & !(page::PAGE_SIZE -1);
 				//上舍入
-	let num_kb_pages = (page::align_val(end, 12) - memaddr) / page::PAGE_SIZE;
+	let len = page::align_val(end, 12) - memaddr;
 
-	for _ in 0..num_kb_pages {
-		page::map(root, memaddr, memaddr, bits, 0);
-		memaddr += 1 << 12;
-	}
+	page::map_range(root, memaddr, memaddr, len, bits);
 }
 
+//启动时想用的分页模式, QEMU的 -cpu rv64 支持Sv48
+const PAGING_MODE: cpu::SatpMode = cpu::SatpMode::Sv48;
+
 extern "C" {
 	fn switch_to_user(frame: usize) -> !;
 }
 
 fn rust_switch_to_user(frame: usize) -> ! {
+	let hart = cpu::hart_id();
+	//进S态的trap时从这里恢复tp
+	unsafe {
+		(*(frame as *mut cpu::TrapFrame)).hartid = hart;
+	}
+	fpu::switch(frame as *mut cpu::TrapFrame, hart);
 	unsafe {
 		switch_to_user(frame);
 	}
@@ -148,121 +159,37 @@ extern "C" fn _kinit(misa: usize, mvendorid: usize, marchid: usize, mimpid: usiz
     panic!("XLY");
 }
 
-//Entry Point
-//注意这之前关闭了中断
-#[no_mangle]
-//extern "C" fn kinit() -> usize {
-extern "C" fn kinit() {
-	uart::Uart::new(0x1000_0000).init();
-
-    //SV39 MMU 分页系统
-	page::init();
-	kmem::init();
-
-    // 注意可能需要进行PLIC地址的页表映射
-	// VIRTIO = [1..8]
-	// UART0 = 10
-	// PCIE = [32..35]
-	println!("Setting up UART interrupts and PLIC...");
-	plic::set_threshold(0);
-	plic::enable(10);
-	plic::set_priority(10, 1);
-
-	console::init();
-
-	/*
-	unsafe {
-		//初始化CLINT timer, 设置下一次时钟中断的触发
-		let mtimecmp = 0x0200_4000 as *mut u64;
-		let mtime = 0x0200_bff8 as *const u64;
-		//QEMU的频率是10_000_000 Hz, 触发在一秒后
-		mtimecmp.write_volatile(mtime.read_volatile() + 10_000_000);
-	}
-	*/
-
-	unsafe {
-		println!("TEXT:        0x{:x} -> 0x{:x}", TEXT_START, TEXT_END);
-		println!("RODATA:      0x{:x} -> 0x{:x}", RODATA_START, RODATA_END);
-		println!("DATA:        0x{:x} -> 0x{:x}", DATA_START, DATA_END);
-		println!("BSS:         0x{:x} -> 0x{:x}", BSS_START, BSS_END);
-		println!("APPS BASE:   0x{:x} -> 0x{:x}", BSS_END, KERNEL_STACK_START);
-		println!("STACK:       0x{:x} -> 0x{:x}", KERNEL_STACK_START, KERNEL_STACK_END);
-	}
-
-    //进入Trap后才能通过mscratch保存TrapFrame
-    unsafe {
-        cpu::mscratch_write((&mut cpu::KERNEL_TRAP_FRAME[0] as *mut cpu::TrapFrame) as usize);
-        cpu::sscratch_write(cpu::mscratch_read());
-		cpu::KERNEL_TRAP_FRAME[0].trap_stack = page::zalloc(1).add(page::PAGE_SIZE);
-        println!("kernel trap frame:{:#x}, trap stack:{:#x}", cpu::mscratch_read() as usize, cpu::KERNEL_TRAP_FRAME[0].trap_stack as usize);
-    }
-
-    let mstatus = mstatus_read(); //为什么读不准mstatus的值?
-    println!("mstatus: {:#x}", mstatus);
-
-    //return;
-
-/////////////////////////////////////////
-
-	let ret = process::init();
-	println!("Init process created at address 0x{:08x}", ret);
-
-	//load into APP_BASE_ADDRESS + app_id * APP_SIZE_LIMIT
-    //
-    loader::load_apps();
-
-	//时钟切片调度
-	//trap::schedule_next_context_switch(1);
-
-    /*
-	let frame = sched::schedule();
-	unsafe {
-		switch_to_user(frame);
-	}
-    */
-
-    syscall(1, 0, 0, 0); //通过ecall来保存KERNEL_TRAP_FRAME, 最终进入switch_to_user()
-
-	// switch_to_user will not return, so we should never get here
-	println!("Jump from U mode! | WE DIDN'T SCHEDULE?! THIS ISN'T RIGHT!");
-
-    return; 
-/////////////////////////////////////////
-
+//内核页表模板: 所有映射都是全局(G)、非用户的, 每个进程的根页表都会装上
+//用kmem里预留的那张根页表
+fn map_kernel() -> usize {
 	let root_ptr = kmem::get_page_table();
 	let root_u = root_ptr as usize;
 	let mut root = unsafe {
 		root_ptr.as_mut().unwrap()
 	};
-	let kheap_head = kmem::get_head() as usize;
-	let total_pages = kmem::get_num_allocations();
-	println!();
-	println!("Kernel HEAP: 0x{:x} -> 0x{:x}", kheap_head, kheap_head + total_pages * 4096);
+	let global = page::EntryBits::Global.val();
 
 	//指定内存范围进行恒等映射，虚拟地址 = 物理地址
-	//kernel堆内存, 可读可写
-	id_map_range(&mut root, kheap_head, kheap_head + total_pages * 4096, page::EntryBits::ReadWrite.val());
-	//与下面的HEAP映射重复了？
-
 	unsafe {
+		//kernel堆内存(kmem)也在HEAP里, 可读可写
 		id_map_range(
 			&mut root,
 			HEAP_START,
-			HEAP_START + HEAP_SIZE / page::PAGE_SIZE,
-			page::EntryBits::ReadWrite.val(),
+			HEAP_START + HEAP_SIZE,
+			page::EntryBits::ReadWrite.val() | global,
 			);
 
 		id_map_range(
 			&mut root,
 			TEXT_START,
 			TEXT_END,
-			page::EntryBits::ReadExecute.val(),
+			page::EntryBits::ReadExecute.val() | global,
 			);
 		id_map_range(
 			&mut root,
 			RODATA_START,
 			RODATA_END,
-			page::EntryBits::ReadExecute.val(),
+			page::EntryBits::ReadExecute.val() | global,
 			);
 		//.text和.rodata都在text, 见.lds
 
@@ -270,41 +197,28 @@ extern "C" fn kinit() {
 			&mut root,
 			DATA_START,
 			DATA_END,
-			page::EntryBits::ReadWrite.val(),
+			page::EntryBits::ReadWrite.val() | global,
 			);
 		id_map_range(
 			&mut root,
 			BSS_START,
 			BSS_END,
-			page::EntryBits::ReadWrite.val(),
+			page::EntryBits::ReadWrite.val() | global,
 			);
 		id_map_range(
 			&mut root,
 			KERNEL_STACK_START,
 			KERNEL_STACK_END,
-			page::EntryBits::ReadWrite.val(),
+			page::EntryBits::ReadWrite.val() | global,
 			);
 	}
 
-	/*
-	//使用页表把一个虚拟地址映射到物理地址
-	// UART
-	page::map( &mut root, 0x1000_0000, 0x1000_0000, page::EntryBits::ReadWrite.val(), 0);
-	// CLINT
-	// -> MSIP
-	page::map( &mut root, 0x0200_0000, 0x0200_0000, page::EntryBits::ReadWrite.val(), 0);
-	// -> MTIMECMP
-	page::map( &mut root, 0x0200_b000, 0x0200_b000, page::EntryBits::ReadWrite.val(), 0);
-	// -> MTIME
-	page::map( &mut root, 0x0200_c000, 0x0200_c000, page::EntryBits::ReadWrite.val(), 0);
-	*/
-
 	//UART
 	id_map_range(
 			&mut root,
 			0x1000_0000,
 			0x1000_0100,
-			page::EntryBits::ReadWrite.val(),
+			page::EntryBits::ReadWrite.val() | global,
 		    );
 
 	//CLINT -> MSIP
@@ -312,7 +226,7 @@ extern "C" fn kinit() {
 			&mut root,
 			0x0200_0000,
 			0x0200_ffff,
-			page::EntryBits::ReadWrite.val(),
+			page::EntryBits::ReadWrite.val() | global,
 		    );
 
 	// PLIC
@@ -320,15 +234,108 @@ extern "C" fn kinit() {
 			&mut root,
 			0x0c00_0000,
 			0x0c00_2001,
-			page::EntryBits::ReadWrite.val(),
+			page::EntryBits::ReadWrite.val() | global,
 		    );
 	id_map_range(
 			&mut root,
 			0x0c20_0000,
 			0x0c20_8001,
-			page::EntryBits::ReadWrite.val(),
+			page::EntryBits::ReadWrite.val() | global,
 		    );
-	//page::print_page_allocations();
+
+	page::set_kernel_root(root_ptr);
+	unsafe {
+		KERNEL_TABLE = root_u;
+	}
+	root_u
+}
+
+//Entry Point
+//注意这之前关闭了中断
+//-bios none: 从boot.S的_start在M态进来, 自己当M态的固件(machine.rs)
+#[no_mangle]
+extern "C" fn kinit() -> usize {
+	uart::Uart::new(0x1000_0000).init();
+	//PMP、trap委派和M态自己的trap帧
+	machine::init_hart();
+	let satp = kinit_common(true);
+
+    let mstatus = mstatus_read(); //为什么读不准mstatus的值?
+    println!("mstatus: {:#x}", mstatus);
+	satp
+}
+
+//SBI固件(OpenSBI等)传来的设备树地址, 还没有解析
+static mut DTB_ADDR: usize = 0;
+
+//-bios default: 固件把内核当S态的payload, 从boot.S的_start_sbi进来, 只有hart 0走这里
+#[no_mangle]
+extern "C" fn kinit_sbi(hartid: usize, dtb: usize) -> usize {
+	uart::Uart::new(0x1000_0000).init();
+	unsafe {
+		DTB_ADDR = dtb;
+	}
+	println!("Booted by SBI firmware on hart {}, device tree @ {:#x}", hartid, dtb);
+	//mcounteren归固件管, 不碰hpmcounter
+	kinit_common(false)
+}
+
+//两种启动方式共用, M态和S态都能跑; 返回内核页表的satp
+fn kinit_common(hpm: bool) -> usize {
+    //SV39 MMU 分页系统
+	page::init();
+	//hart不支持Sv48时退回Sv39; 必须在创建任何页表之前
+	page::set_mode(page::probe_mode(PAGING_MODE));
+	println!("Paging mode: Sv{}", 12 + page::levels() * 9);
+	asid::init();
+	kmem::init();
+	perf::init_hart(hpm);
+
+    // 注意可能需要进行PLIC地址的页表映射
+	// VIRTIO = [1..8]
+	// UART0 = 10
+	// PCIE = [32..35]
+	println!("Setting up UART interrupts and PLIC...");
+	plic::set_threshold(0);
+	plic::enable(10);
+	plic::set_priority(10, 1);
+
+	console::init();
+
+	/*
+	unsafe {
+		//初始化CLINT timer, 设置下一次时钟中断的触发
+		let mtimecmp = 0x0200_4000 as *mut u64;
+		let mtime = 0x0200_bff8 as *const u64;
+		//QEMU的频率是10_000_000 Hz, 触发在一秒后
+		mtimecmp.write_volatile(mtime.read_volatile() + 10_000_000);
+	}
+	*/
+
+	unsafe {
+		println!("TEXT:        0x{:x} -> 0x{:x}", TEXT_START, TEXT_END);
+		println!("RODATA:      0x{:x} -> 0x{:x}", RODATA_START, RODATA_END);
+		println!("DATA:        0x{:x} -> 0x{:x}", DATA_START, DATA_END);
+		println!("BSS:         0x{:x} -> 0x{:x}", BSS_START, BSS_END);
+		println!("STACK:       0x{:x} -> 0x{:x}", KERNEL_STACK_START, KERNEL_STACK_END);
+	}
+
+	//内核的一半地址空间, 之后每个进程都共享
+	let kernel_root = map_kernel();
+	println!("Kernel page table: 0x{:x}", kernel_root);
+
+    //进入S态的Trap后才能通过sscratch保存TrapFrame
+    unsafe {
+        cpu::sscratch_write((&mut cpu::KERNEL_TRAP_FRAME[0] as *mut cpu::TrapFrame) as usize);
+		cpu::KERNEL_TRAP_FRAME[0].satp = cpu::build_satp(page::satp_mode(), 0, kernel_root);
+		trap::alloc_trap_stacks();
+		cpu::KERNEL_TRAP_FRAME[0].trap_stack = trap::trap_stack(0);
+        println!("kernel trap frame:{:#x}, trap stack:{:#x}", cpu::sscratch_read() as usize, cpu::KERNEL_TRAP_FRAME[0].trap_stack as usize);
+    }
+	KERNEL_READY.store(true, Ordering::Release);
+
+	//返回值给boot.S中的SATP寄存器, 然后进入S态的kmain(), 进程也从那里开始调度
+	return unsafe { cpu::KERNEL_TRAP_FRAME[0].satp };
 
 	/*
 	let satp_value = cpu::build_satp(cpu::SatpMode::Sv39, 0, root_u);
@@ -372,7 +379,7 @@ extern "C" fn kinit() {
 	unsafe {
 		KERNEL_TABLE = root_u;
 	}
-	//返回值给boot.S中的SATP寄存器, 然后将切换进supervisor mode
+	//返回值给boot.S中的SATP寄存器
 	//内核根页表, 除于4K, 把低12位清掉
 	(root_u >> 12) | (8 << 60)
 	*/
@@ -384,19 +391,48 @@ extern "C" fn kinit() {
 }
 
 #[no_mangle]
-extern "C" fn kinit_hart(hartid: usize) {
-	//全部非０harts核在这初始化
+extern "C" fn kinit_hart(hartid: usize) -> usize {
+	//全部非０harts核在这初始化, 返回后进入S态
+	machine::init_hart();
+	kinit_hart_common(hartid, true)
+}
+
+//SBI固件下的非0 hart, 由kmain()通过HSM叫醒, 已经在S态
+#[no_mangle]
+extern "C" fn kinit_hart_sbi(hartid: usize) -> usize {
+	kinit_hart_common(hartid, false)
+}
+
+fn kinit_hart_common(hartid: usize, hpm: bool) -> usize {
 	unsafe {
-		cpu::mscratch_write((&mut cpu::KERNEL_TRAP_FRAME[hartid] as *mut cpu::TrapFrame) as usize);
-		//相同mscratch寄存器的值复制给Ｓ态的sscratch
-		cpu::sscratch_write(cpu::mscratch_read());
+		cpu::sscratch_write((&mut cpu::KERNEL_TRAP_FRAME[hartid] as *mut cpu::TrapFrame) as usize);
 		cpu::KERNEL_TRAP_FRAME[hartid].hartid = hartid;
+	}
+	perf::init_hart(hpm);
+
+	//分配器加了锁, 但要等hart 0初始化完才能用
+	while !KERNEL_READY.load(Ordering::Acquire) {}
+	unsafe {
+		cpu::KERNEL_TRAP_FRAME[hartid].satp = cpu::KERNEL_TRAP_FRAME[0].satp;
+		cpu::KERNEL_TRAP_FRAME[hartid].trap_stack = trap::trap_stack(hartid);
+		cpu::KERNEL_TRAP_FRAME[hartid].satp
+	}
+}
+
+extern "C" {
+	fn _start_sbi_hart();
+}
 
-		//需要zalloc()锁
-		// We can't do the following until zalloc() is locked, but we
-		// don't have locks, yet :( 
-		// cpu::KERNEL_TRAP_FRAME[hartid].satp = cpu::KERNEL_TRAP_FRAME[0].satp;
-		// cpu::KERNEL_TRAP_FRAME[hartid].trap_stack = page::zalloc(1);
+//SBI固件下其他hart停着等HSM叫醒; -bios none时它们自己从_start起来了, machine.rs也没有HSM
+fn start_harts() {
+	if !sbi::has_hsm() {
+		return;
+	}
+	for hart in 1..cpu::MAX_HARTS {
+		//没有这个hart或者它已经在跑了都会失败
+		if sbi::hart_start(hart, _start_sbi_hart as usize, 0).is_ok() {
+			println!("Started hart {}", hart);
+		}
 	}
 }
 
@@ -422,6 +458,11 @@ fn kmain() {
 		//println!("SOS by xiaoluoyuan@163.com\nHeap start @ 0x{:x}", _stack_end); // ? 有问题
 	}
 
+	//M态的kinit()里还不能ecall, 到S态了再探测固件
+	sbi::init();
+	start_harts();
+
+	kmem::leak_begin();
 	{
 		//在堆上存储u32类型的数据, 应用了global allocator
 		let k = Box::<u32>::new(100);
@@ -433,7 +474,12 @@ fn kmain() {
 		let sparkle_heart = String::from_utf8(sparkle_heart).unwrap();
 		println!("String = {}", sparkle_heart);
 		kmem::print_table();
+		kmem::print_caches();
 	}
+	kmem::leak_end("kmain heap demo");
+	kmem::print_stats();
+	#[cfg(feature = "kmem-bench")]
+	kbench::run();
 	println!("\nEverything should now be free now");
 	//到这后，Box, vec和String的内存应该被释放了，因为出了括号的范围
 	//kmem::print_table();
@@ -442,6 +488,22 @@ fn kmain() {
         llvm_asm!("ebreak"::::"volatile");
     }
 
+	let ret = process::init();
+	println!("Init process created at address 0x{:08x}", ret);
+
+	//每个app复制到自己的物理页, 进程里映射在APP_BASE_ADDRESS
+    //
+    loader::load_apps();
+
+	//时钟切片调度
+	//trap::schedule_next_context_switch(1);
+
+	//S态的ecall会进M态, 改成给自己发软件中断进trap来保存KERNEL_TRAP_FRAME, 最终进入switch_to_user()
+	trap::enter_scheduler();
+
+	//所有进程都退出后回到这里
+	println!("Jump from U mode! | WE DIDN'T SCHEDULE?! THIS ISN'T RIGHT!");
+
 	println!("I'm so awesome. If you start typing something, I'll show you what you typed!");
 	/*
 	loop {
@@ -498,6 +560,7 @@ pub mod uart;
 pub mod page;
 pub mod kmem;
 pub mod cpu;
+pub mod csr;
 pub mod trap;
 pub mod plic;
 pub mod process;
@@ -507,6 +570,15 @@ pub mod sched;
 pub mod fs;
 pub mod console;
 pub mod lock;
+pub mod asid;
+pub mod fpu;
+pub mod machine;
+pub mod sbi;
+pub mod misaligned;
+pub mod backtrace;
+pub mod perf;
+#[cfg(feature = "kmem-bench")]
+pub mod kbench;
 
 pub mod loader;
 
@@ -1,14 +1,12 @@
-use crate::process::add_process_default;
-
-extern "C" {
-	static BSS_END: usize;
-}
+use crate::page::{page_ref_dec, split_block, zalloc, PAGE_SIZE};
+use crate::process::{add_process_image, PROCESS_STARTING_ADDR};
+use crate::sbi;
 
 pub const USER_STACK_SIZE: usize = 4096 * 2;
 pub const KERNEL_STACK_SIZE: usize = 4096 * 2;
 pub const MAX_APP_NUM: usize = 4;
-pub const APP_BASE_ADDRESS: usize = 0x80100000;
-//pub const APP_BASE_ADDRESS: usize = 0x80100000;
+//app都要链接在这个用户虚拟地址, 入口在开头; 每个app的镜像放在从页分配器分的物理页里
+pub const APP_BASE_ADDRESS: usize = PROCESS_STARTING_ADDR;
 pub const APP_SIZE_LIMIT: usize = 0x20000;
 
 /*
@@ -58,11 +56,6 @@ impl UserStack {
 }
 */
 
-fn get_base_i(app_id: usize) -> usize {
-    APP_BASE_ADDRESS + app_id * APP_SIZE_LIMIT
-    //unsafe { BSS_END + app_id * APP_SIZE_LIMIT }
-}
-
 pub fn get_num_app() -> usize {
     extern "C" { fn _num_app(); }
     unsafe { (_num_app as usize as *const usize).read_volatile() }
@@ -75,33 +68,38 @@ pub fn load_apps() {
     let app_start = unsafe {
         core::slice::from_raw_parts(num_app_ptr.add(1), num_app + 1)
     };
-    // clear i-cache first
-    unsafe { llvm_asm!("fence.i" :::: "volatile"); }
     // load apps
     for i in 0..num_app {
-        let base_i = get_base_i(i);
-        // clear region
-        (base_i..base_i + APP_SIZE_LIMIT).for_each(|addr| unsafe {
-            (addr as *mut u8).write_volatile(0)
-        });
+        // 清零的页, 镜像后面剩下的部分就是bss
+        let image = zalloc(APP_SIZE_LIMIT / PAGE_SIZE);
+        assert!(!image.is_null());
         // load app from data section to memory
         let src = unsafe {
             core::slice::from_raw_parts(app_start[i] as *const u8, app_start[i + 1] - app_start[i])
         };
+        assert!(src.len() <= APP_SIZE_LIMIT);
         let dst = unsafe {
-            core::slice::from_raw_parts_mut(base_i as *mut u8, src.len())
+            core::slice::from_raw_parts_mut(image, src.len())
         };
         dst.copy_from_slice(src);
 
 	/////
-	println!("Loading app 0x{:x}", base_i);
-
-	let app_start_ptr;
-	unsafe {
-	//不安全的强制类型转换
-		app_start_ptr = core::mem::transmute::<usize, fn()>(base_i);
+	println!("Loading app {} at 0x{:x}", i, image as usize);
+	// 拆成单页, 进程按页共享镜像、写时复制
+	split_block(image);
+	add_process_image(image, APP_SIZE_LIMIT);
+	// 第一个app起两个进程共享同一个镜像, 谁先写哪页谁复制
+	if i == 0 {
+		add_process_image(image, APP_SIZE_LIMIT);
+	}
+	// 进程各自持有引用了, 放掉loader自己的
+	for page in (image as usize..image as usize + APP_SIZE_LIMIT).step_by(PAGE_SIZE) {
+		page_ref_dec(page);
 	}
-	add_process_default(app_start_ptr);
+    }
+    // 写进去的是指令, 进程可能在任何hart上跑, 取指前都要fence.i
+    if let Err(e) = sbi::remote_fence_i(0) {
+        panic!("Remote fence.i failed: {}", e);
     }
 }
 
@@ -0,0 +1,255 @@
+// machine.rs
+// M态剩下的一层
+//
+// 内核本身运行在S态, 异常和S态的中断都委派给S态的s_trap(见trap.rs);
+// M态只处理委派不了的: 机器时钟中断和软件中断(IPI)转成S态的STIP/SSIP, 以及S态的ecall.
+// S态的ecall按SBI的约定(见sbi.rs), 只实现了sbi.rs会用到的最少几个扩展;
+// 发给别的hart的请求(IPI, RFENCE)记在它的PENDING里, 再用CLINT的MSIP叫它进M态处理;
+// 用OpenSBI等固件启动时(-bios default)没有这一层, 内核从_start_sbi进来
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::cpu::{dump_registers, gp, mhartid_read, mscratch_write, Registers, TrapFrame, MAX_HARTS};
+use crate::csr::{self, Cause, Counteren, Exception, Exceptions, Interrupt, Interrupts, Pmp, PmpMatch};
+use crate::sbi::{EID_BASE, EID_IPI, EID_LEGACY_PUTCHAR, EID_LEGACY_SET_TIMER, EID_RFENCE, EID_SOS, EID_TIME, IMPL_ID_SOS, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED, SBI_SUCCESS};
+
+//CLINT: 每个hart一个MSIP(4字节)和一个mtimecmp(8字节)
+const CLINT_MSIP: usize = 0x0200_0000;
+const CLINT_MTIMECMP: usize = 0x0200_4000;
+
+//SBI v0.2
+const SBI_SPEC_VERSION: usize = 2;
+
+const MACHINE_STACK_SIZE: usize = 4096;
+
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+struct MachineStack([u8; MACHINE_STACK_SIZE]);
+
+//M态trap保存通用寄存器的地方(mscratch)和它用的栈, M态不开MMU, 直接用物理地址
+static mut MACHINE_TRAP_FRAME: [TrapFrame; MAX_HARTS] = [TrapFrame::zero(); MAX_HARTS];
+static mut MACHINE_STACK: [MachineStack; MAX_HARTS] = [MachineStack([0; MACHINE_STACK_SIZE]); MAX_HARTS];
+
+//别的hart托本hart做的事, 在MachineSoft中断里做完再清掉对应的位
+const PENDING_SSIP: usize = 1 << 0;
+const PENDING_SFENCE_VMA: usize = 1 << 1;
+const PENDING_FENCE_I: usize = 1 << 2;
+static mut PENDING: [usize; MAX_HARTS] = [0; MAX_HARTS];
+
+//跑过init_hart()的hart; 没启动的hart收不到MSIP, RFENCE不能等它们
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+//每个hart在M态启动时调用: 设置PMP、委派、中断使能和M态的trap帧
+pub fn init_hart() {
+	let hart = mhartid_read();
+	unsafe {
+		MACHINE_TRAP_FRAME[hart].hartid = hart;
+		MACHINE_TRAP_FRAME[hart].trap_stack = MACHINE_STACK[hart].0.as_mut_ptr().add(MACHINE_STACK_SIZE);
+		mscratch_write(&mut MACHINE_TRAP_FRAME[hart] as *mut TrapFrame as usize);
+	}
+	//还没写satp, 之后改的页表项不会在这个hart的TLB里
+	ONLINE.fetch_or(1 << hart, Ordering::AcqRel);
+
+	//实现了PMP时, 没有任何表项的话S/U态什么都访问不了; 第0项放开整个地址空间
+	csr::pmp_set(0, Pmp::new(Pmp::R | Pmp::W | Pmp::X, PmpMatch::Napot), usize::MAX);
+
+	//S态的ecall留给M态, 其他异常都交给S态
+	let exceptions = Exceptions::new()
+		.with(Exception::InstructionMisaligned)
+		.with(Exception::InstructionFault)
+		.with(Exception::IllegalInstruction)
+		.with(Exception::Breakpoint)
+		.with(Exception::LoadMisaligned)
+		.with(Exception::LoadFault)
+		.with(Exception::StoreMisaligned)
+		.with(Exception::StoreFault)
+		.with(Exception::UserEcall)
+		.with(Exception::InstructionPageFault)
+		.with(Exception::LoadPageFault)
+		.with(Exception::StorePageFault);
+	csr::medeleg::write(exceptions);
+	let s_irqs = Interrupts::new()
+		.with(Interrupt::SupervisorSoft)
+		.with(Interrupt::SupervisorTimer)
+		.with(Interrupt::SupervisorExternal);
+	csr::mideleg::write(s_irqs);
+
+	//S/U态能直接读cycle/time/instret/hpmcounter, 给perf.rs和get_mtime()用
+	csr::mcounteren::write(Counteren::new().with_cy(true).with_tm(true).with_ir(true)
+		.with_hpm(3, true).with_hpm(4, true).with_hpm(5, true).with_hpm(6, true));
+
+	//MTIE等S态第一次设置时钟时再打开
+	csr::mie::write(s_irqs.with(Interrupt::MachineSoft));
+}
+
+fn msip(hart: usize) -> *mut u32 {
+	(CLINT_MSIP + hart * 4) as *mut u32
+}
+
+fn mtimecmp(hart: usize) -> *mut u64 {
+	(CLINT_MTIMECMP + hart * 8) as *mut u64
+}
+
+//AtomicUsize和usize的内存布局相同
+fn pending(hart: usize) -> &'static AtomicUsize {
+	unsafe { &*(&PENDING[hart] as *const usize as *const AtomicUsize) }
+}
+
+//做完别的hart托过来的事; 先做再清位, 发起的hart看到清零时已经做完了
+fn run_pending(hart: usize) {
+	let ops = pending(hart).load(Ordering::Acquire);
+	if ops & PENDING_SFENCE_VMA != 0 {
+		unsafe { llvm_asm!("sfence.vma" :::: "volatile"); }
+	}
+	if ops & PENDING_FENCE_I != 0 {
+		unsafe { llvm_asm!("fence.i" :::: "volatile"); }
+	}
+	if ops & PENDING_SSIP != 0 {
+		csr::mip::set(Interrupt::SupervisorSoft.mask());
+	}
+	pending(hart).fetch_and(!ops, Ordering::AcqRel);
+}
+
+//SBI的hart掩码: mask第0位对应hart base, base为usize::MAX时表示所有hart
+fn hart_hit(i: usize, mask: usize, base: usize) -> bool {
+	base == usize::MAX || (i >= base && i - base < 64 && mask >> (i - base) & 1 != 0)
+}
+
+//让掩码里在线的hart刷新TLB或者指令缓存, 等它们都做完才返回; 本hart的直接做.
+//等的时候顺便处理别人托给自己的, 两个hart同时互相发RFENCE时不会死等
+fn remote_fence(hart: usize, mask: usize, base: usize, ops: usize) {
+	let online = ONLINE.load(Ordering::Acquire);
+	let targets = (0..MAX_HARTS).filter(|&i| i != hart && online >> i & 1 != 0 && hart_hit(i, mask, base));
+	for i in targets.clone() {
+		pending(i).fetch_or(ops, Ordering::AcqRel);
+		unsafe { msip(i).write_volatile(1); }
+	}
+	if hart_hit(hart, mask, base) {
+		pending(hart).fetch_or(ops, Ordering::AcqRel);
+		run_pending(hart);
+	}
+	for i in targets {
+		while pending(i).load(Ordering::Acquire) & ops != 0 {
+			run_pending(hart);
+		}
+	}
+}
+
+#[no_mangle]
+extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, status: usize, frame: *mut TrapFrame) -> usize {
+	let cause = Cause(cause);
+	if cause.is_interrupt() {
+		match cause.interrupt() {
+			//关掉MTIE免得一直进来, 置位STIP交给S态; S态设置下一次时钟时再打开
+			Some(Interrupt::MachineTimer) => {
+				csr::mie::clear(Interrupt::MachineTimer.mask());
+				csr::mip::set(Interrupt::SupervisorTimer.mask());
+			},
+			//清掉CLINT里的MSIP, 做别的hart托过来的事; IPI就是置位SSIP
+			Some(Interrupt::MachineSoft) => {
+				unsafe { msip(hart).write_volatile(0); }
+				run_pending(hart);
+			},
+			_ => {
+				panic!("Unhandled machine interrupt CPU#{} -> {}\n", hart, cause.code());
+			}
+		}
+		return epc;
+	}
+
+	match cause.exception() {
+		Some(Exception::SupervisorEcall) => unsafe {
+			let regs = &mut (*frame).regs;
+			let (err, val) = sbi_handle(hart, regs[gp(Registers::A7)], regs[gp(Registers::A6)], regs[gp(Registers::A0)], regs[gp(Registers::A1)]);
+			regs[gp(Registers::A0)] = err as usize;
+			regs[gp(Registers::A1)] = val;
+			epc + 4
+		},
+		_ => {
+			dump_registers(frame);
+			panic!("Unhandled machine trap CPU#{} -> {}: 0x{:08x}: 0x{:08x}, mstatus: {:#x}\n", hart, cause.code(), epc, tval, status);
+		}
+	}
+}
+
+fn sbi_handle(hart: usize, eid: usize, fid: usize, a0: usize, a1: usize) -> (isize, usize) {
+	match eid {
+		EID_BASE => match fid {
+			0 => (SBI_SUCCESS, SBI_SPEC_VERSION),
+			3 => (SBI_SUCCESS, probe(a0) as usize),
+			4 => (SBI_SUCCESS, csr::mvendorid::read()),
+			5 => (SBI_SUCCESS, csr::marchid::read()),
+			6 => (SBI_SUCCESS, csr::mimpid::read()),
+			//实现号没有注册过, 版本给0
+			1 => (SBI_SUCCESS, IMPL_ID_SOS),
+			2 => (SBI_SUCCESS, 0),
+			_ => (SBI_ERR_NOT_SUPPORTED, 0),
+		},
+		EID_TIME if fid == 0 => {
+			set_timer_local(hart, a0);
+			(SBI_SUCCESS, 0)
+		},
+		EID_LEGACY_SET_TIMER => {
+			set_timer_local(hart, a0);
+			(SBI_SUCCESS, 0)
+		},
+		//a0 = hart掩码, a1 = 掩码第0位对应的hart号, 为usize::MAX时表示所有hart
+		EID_IPI if fid == 0 => {
+			for i in 0..MAX_HARTS {
+				if hart_hit(i, a0, a1) {
+					pending(i).fetch_or(PENDING_SSIP, Ordering::AcqRel);
+					unsafe { msip(i).write_volatile(1); }
+				}
+			}
+			(SBI_SUCCESS, 0)
+		},
+		//remote fence.i / sfence.vma / sfence.vma带ASID; 地址范围和ASID不看, 整个刷掉
+		EID_RFENCE if fid == 0 => {
+			remote_fence(hart, a0, a1, PENDING_FENCE_I);
+			(SBI_SUCCESS, 0)
+		},
+		EID_RFENCE if fid == 1 || fid == 2 => {
+			remote_fence(hart, a0, a1, PENDING_SFENCE_VMA);
+			(SBI_SUCCESS, 0)
+		},
+		EID_LEGACY_PUTCHAR => {
+			print!("{}", a0 as u8 as char);
+			(SBI_SUCCESS, 0)
+		},
+		//自定义扩展: a0 = 第几个可编程计数器, a1 = 事件号; 见perf::set_event()
+		EID_SOS if fid == 0 => {
+			if set_hpm_event(a0, a1) {
+				(SBI_SUCCESS, 0)
+			}else{
+				(SBI_ERR_INVALID_PARAM, 0)
+			}
+		},
+		_ => (SBI_ERR_NOT_SUPPORTED, 0),
+	}
+}
+
+fn probe(eid: usize) -> bool {
+	match eid {
+		EID_BASE | EID_TIME | EID_IPI | EID_RFENCE | EID_SOS | EID_LEGACY_SET_TIMER | EID_LEGACY_PUTCHAR => true,
+		_ => false,
+	}
+}
+
+//mhpmcounter(3+i)计event事件并清零
+fn set_hpm_event(i: usize, event: usize) -> bool {
+	match i {
+		0 => { csr::mhpmevent3::write(event); csr::mhpmcounter3::write(0); },
+		1 => { csr::mhpmevent4::write(event); csr::mhpmcounter4::write(0); },
+		2 => { csr::mhpmevent5::write(event); csr::mhpmcounter5::write(0); },
+		3 => { csr::mhpmevent6::write(event); csr::mhpmcounter6::write(0); },
+		_ => return false,
+	}
+	true
+}
+
+fn set_timer_local(hart: usize, stime: usize) {
+	unsafe {
+		mtimecmp(hart).write_volatile(stime as u64);
+	}
+	csr::mip::clear(Interrupt::SupervisorTimer.mask());
+	csr::mie::set(Interrupt::MachineTimer.mask());
+}
@@ -0,0 +1,160 @@
+// misaligned.rs
+// 非对齐load/store的模拟
+//
+// 硬件不支持非对齐访问时报Load/StoreMisaligned(已委派给S态): 解码出错的指令(包括压缩指令),
+// 按字节通过出错进程的页表读写内存, load的结果写回TrapFrame里的rd, pc跳过这条指令.
+// 只模拟整数的load/store; 浮点的寄存器可能还在FPU里没存进TrapFrame, AMO要求原子, 都不模拟
+
+use crate::cpu::{gp, CpuMode, Registers, TrapFrame};
+use crate::csr::Sstatus;
+use crate::page::{kernel_root, lookup, EntryBits};
+use crate::process::user_virt_to_phys;
+use crate::trap::trap_stack_contains;
+
+//解码出来的一次访存
+struct Access {
+	store:  bool,
+	width:  usize,  //1, 2, 4, 8字节
+	signed: bool,   //load的结果是否符号扩展
+	reg:    usize,  //load的rd, store的rs2
+	base:   usize,  //rs1
+	offset: isize,
+	len:    usize,  //指令长度, 2或4
+}
+
+//insn的[hi:lo]位
+fn field(insn: usize, hi: usize, lo: usize) -> usize {
+	(insn >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+fn decode(insn: usize) -> Option<Access> {
+	if insn & 0b11 != 0b11 {
+		return decode_compressed(insn);
+	}
+	let funct3 = field(insn, 14, 12);
+	let base = field(insn, 19, 15);
+	match insn & 0x7f {
+		//LOAD: lb lh lw ld lbu lhu lwu
+		0x03 => {
+			let (width, signed) = match funct3 {
+				0 => (1, true),
+				1 => (2, true),
+				2 => (4, true),
+				3 => (8, true),
+				4 => (1, false),
+				5 => (2, false),
+				6 => (4, false),
+				_ => return None,
+			};
+			let offset = ((insn as u32 as i32) >> 20) as isize;
+			Some(Access { store: false, width, signed, reg: field(insn, 11, 7), base, offset, len: 4 })
+		},
+		//STORE: sb sh sw sd
+		0x23 if funct3 <= 3 => {
+			let offset = ((((insn as u32 as i32) >> 25) << 5) as isize) | field(insn, 11, 7) as isize;
+			Some(Access { store: true, width: 1 << funct3, signed: false, reg: field(insn, 24, 20), base, offset, len: 4 })
+		},
+		_ => None,
+	}
+}
+
+//c.lw c.ld c.sw c.sd(寄存器是x8-x15), c.lwsp c.ldsp c.swsp c.sdsp(基址是sp); 偏移都是无符号的
+fn decode_compressed(insn: usize) -> Option<Access> {
+	let funct3 = field(insn, 15, 13);
+	let bit = |i: usize| (insn >> i) & 1;
+	let (store, width, reg, base, offset) = match (insn & 0b11, funct3) {
+		(0b00, 0b010) | (0b00, 0b110) => {
+			let off = field(insn, 12, 10) << 3 | bit(6) << 2 | bit(5) << 6;
+			(funct3 == 0b110, 4, 8 + field(insn, 4, 2), 8 + field(insn, 9, 7), off)
+		},
+		(0b00, 0b011) | (0b00, 0b111) => {
+			let off = field(insn, 12, 10) << 3 | field(insn, 6, 5) << 6;
+			(funct3 == 0b111, 8, 8 + field(insn, 4, 2), 8 + field(insn, 9, 7), off)
+		},
+		(0b10, 0b010) if field(insn, 11, 7) != 0 => {
+			let off = bit(12) << 5 | field(insn, 6, 4) << 2 | field(insn, 3, 2) << 6;
+			(false, 4, field(insn, 11, 7), 2, off)
+		},
+		(0b10, 0b011) if field(insn, 11, 7) != 0 => {
+			let off = bit(12) << 5 | field(insn, 6, 5) << 3 | field(insn, 4, 2) << 6;
+			(false, 8, field(insn, 11, 7), 2, off)
+		},
+		(0b10, 0b110) => {
+			let off = field(insn, 12, 9) << 2 | field(insn, 8, 7) << 6;
+			(true, 4, field(insn, 6, 2), 2, off)
+		},
+		(0b10, 0b111) => {
+			let off = field(insn, 12, 10) << 3 | field(insn, 9, 7) << 6;
+			(true, 8, field(insn, 6, 2), 2, off)
+		},
+		_ => return None,
+	};
+	//压缩的load只有c.lw/c.lwsp和64位的, 都是符号扩展
+	Some(Access { store, width, signed: true, reg, base, offset: offset as isize, len: 2 })
+}
+
+//U态出错的按进程的页表(顺便解决按需分页和写时复制), S态出错的按内核页表;
+//两边都要求映射有对应的R/W位, 不能替指令绕过页的权限
+unsafe fn phys(frame: *const TrapFrame, user: bool, vaddr: usize, write: bool) -> Option<usize> {
+	if user {
+		return user_virt_to_phys((*frame).pid as u16, vaddr, write);
+	}
+	let need = if write { EntryBits::Write.val() } else { EntryBits::Read.val() };
+	match lookup(&*kernel_root(), vaddr) {
+		Some(m) if m.flags & need != 0 => Some(m.paddr | (vaddr & (m.size() - 1))),
+		_ => None,
+	}
+}
+
+//按半字读, 32位指令的后一半可能在下一页
+unsafe fn read_insn(frame: *const TrapFrame, user: bool, epc: usize) -> Option<usize> {
+	let lo = (phys(frame, user, epc, false)? as *const u16).read_volatile() as usize;
+	if lo & 0b11 != 0b11 {
+		return Some(lo);
+	}
+	let hi = (phys(frame, user, epc + 2, false)? as *const u16).read_volatile() as usize;
+	Some(lo | hi << 16)
+}
+
+//Load/StoreMisaligned时调用, store表示是哪一种; 模拟成功返回下一条指令的pc,
+//指令不认识或者地址不能访问时返回None, 由调用者按原来的异常处理
+pub fn emulate(frame: *mut TrapFrame, epc: usize, status: usize, store: bool) -> Option<usize> {
+	unsafe {
+		let user = Sstatus(status).spp() == CpuMode::User;
+		//在trap处理函数里又出错: s_trap_vector已经用同一个frame和trap栈顶覆盖了外层的现场, 回不去了
+		if !user && trap_stack_contains((*frame).regs[gp(Registers::Sp)]) {
+			return None;
+		}
+		let a = decode(read_insn(frame, user, epc)?)?;
+		if a.store != store {
+			return None;
+		}
+		let vaddr = (*frame).regs[a.base].wrapping_add(a.offset as usize);
+
+		//先把每个字节都翻译好, 免得写到一半才发现后一页不能访问
+		let mut paddr = [0usize; 8];
+		for i in 0..a.width {
+			paddr[i] = phys(frame, user, vaddr.wrapping_add(i), store)?;
+		}
+
+		if store {
+			let val = if a.reg == 0 { 0 } else { (*frame).regs[a.reg] };
+			for i in 0..a.width {
+				(paddr[i] as *mut u8).write_volatile((val >> (8 * i)) as u8);
+			}
+		}else{
+			let mut val = 0usize;
+			for i in 0..a.width {
+				val |= ((paddr[i] as *const u8).read_volatile() as usize) << (8 * i);
+			}
+			if a.signed && a.width < 8 {
+				let shift = 64 - 8 * a.width;
+				val = (((val << shift) as isize) >> shift) as usize;
+			}
+			if a.reg != 0 {
+				(*frame).regs[a.reg] = val;
+			}
+		}
+		Some(epc + a.len)
+	}
+}
@@ -1,4 +1,7 @@
-use core::{mem::size_of, ptr::null_mut};
+use core::{marker::PhantomData, mem::size_of, ptr::null_mut};
+use crate::cpu::{build_satp, hart_id, irq_disable, irq_restore, return_address, satp_fence, satp_fence_all, satp_fence_asid, satp_fence_global, satp_read, satp_write, SatpMode, MAX_HARTS};
+use crate::lock::Mutex;
+use crate::sbi;
 
 extern "C" {
 	static HEAP_START: usize;
@@ -6,26 +9,36 @@ extern "C" {
 }
 
 static mut ALLOC_START: usize = 0;
+//真正可分配的页数, 去掉了页描述符占用的空间
+static mut NUM_PAGES: usize = 0;
 const PAGE_ORDER: usize = 12;
 pub const PAGE_SIZE: usize = 1 << 12;
 
+//伙伴系统的阶数: order n 的块有 2^n 个连续页, 最大块 2^(MAX_ORDER-1) = 1024页 = 4M
+pub const MAX_ORDER: usize = 11;
+
 pub const fn align_val(val: usize, order: usize) -> usize {
 	let o = (1usize << order) - 1;
 	(val + o) & !o
 }
 
-/*
+//空闲块链表节点, 直接存放在空闲块的第一页里
 struct FreePages {
-	struct FreePages *next;
+	next: *mut FreePages,
+	prev: *mut FreePages,
 }
-*/
 
-//每页的1byte标志
+//每个阶一条双向空闲链表, 以及该阶空闲块的个数
+static mut FREE_LISTS: [*mut FreePages; MAX_ORDER] = [null_mut(); MAX_ORDER];
+static mut FREE_COUNT: [usize; MAX_ORDER] = [0; MAX_ORDER];
+
+//每页的1byte标志, 只有块的第一页(head)才有标志
 #[repr(u8)]
 pub enum PageBits {
 	Empty = 0,
-	Taken = 1 << 0,
-	Last = 1 << 1,
+	Taken = 1 << 0, //已分配块的head
+	Free = 1 << 1,  //空闲链表中的块的head
+	Poisoned = 1 << 2, //空闲块已经填满了毒值(page-debug)
 }
 
 impl PageBits {
@@ -34,31 +47,48 @@ impl PageBits {
 	}
 }
 
+//refcnt是整个块的引用计数, 只记在块的head页上; 单页块即每个物理页一个计数
 pub struct Page {
 	flags: u8,
+	order: u8,
+	refcnt: u16,
+	//page-debug: 最后一次分配、释放这一页的调用者(返回地址), 块里的每一页都记
+	#[cfg(feature = "page-debug")]
+	alloc_ra: usize,
+	#[cfg(feature = "page-debug")]
+	free_ra: usize,
 }
 
 impl Page {
-	pub fn is_last(&self) -> bool {
-		if self.flags & PageBits::Last.val() != 0 {
+	pub fn is_taken(&self) -> bool {
+		if self.flags & PageBits::Taken.val() != 0 {
 			true
 		}else{
 			false
 		}
 	}
-	pub fn is_taken(&self) -> bool {
-		if self.flags & PageBits::Taken.val() != 0 {
+	pub fn is_free(&self) -> bool {
+		if self.flags & PageBits::Free.val() != 0 {
 			true
 		}else{
 			false
 		}
 	}
-	pub fn is_free(&self) -> bool {
-		!self.is_taken()
+	pub fn is_poisoned(&self) -> bool {
+		self.flags & PageBits::Poisoned.val() != 0
+	}
+	pub fn get_order(&self) -> usize {
+		self.order as usize
+	}
+
+	pub fn get_refcnt(&self) -> usize {
+		self.refcnt as usize
 	}
 
 	pub fn clear(&mut self) {
 		self.flags = PageBits::Empty.val();
+		self.order = 0;
+		self.refcnt = 0;
 	}
 	pub fn set_flag(&mut self, flag: PageBits) {
 		self.flags |= flag.val();
@@ -66,94 +96,369 @@ impl Page {
 	pub fn clear_flag(&mut self, flag: PageBits) {
 		self.flags &= !(flag.val());
 	}
+	pub fn set_order(&mut self, order: usize) {
+		self.order = order as u8;
+	}
+	pub fn ref_inc(&mut self) {
+		assert!(self.refcnt < u16::MAX, "Page reference count overflow");
+		self.refcnt += 1;
+	}
+	pub fn ref_dec(&mut self) -> usize {
+		self.refcnt -= 1;
+		self.refcnt as usize
+	}
 }
 
-// ... stack | u8 Page structure | u8 page-structure | ... | 4096 bytes page | 4096 bytes page | ...
-//           ^                                             ^
-//        HEAP_START     真正可分配内存的起始地址: ALLOC_START = ((HEAP_START + num_pages * u8 ) + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1)
-// ALLOC_START地址进行了上舍入,对齐到4096页边界； 可能不为整页的地址~~~~~~~~~~~~~~~~~~~~~~~~~~~
-pub fn init() {
-	unsafe {
-		let num_pages = HEAP_SIZE / PAGE_SIZE;
-		let ptr = HEAP_START as *mut Page;
+//页号 -> 页描述符
+unsafe fn page_desc(idx: usize) -> *mut Page {
+	(HEAP_START as *mut Page).add(idx)
+}
 
-		for i in 0..num_pages {
-			(*ptr.add(i)).clear();
+unsafe fn page_addr(idx: usize) -> usize {
+	ALLOC_START + idx * PAGE_SIZE
+}
+
+unsafe fn page_index(addr: usize) -> usize {
+	(addr - ALLOC_START) / PAGE_SIZE
+}
+
+//物理地址是否归页分配器管理; 内核镜像和载入的app不在堆里
+pub fn is_managed(addr: usize) -> bool {
+	unsafe { addr >= ALLOC_START && addr < ALLOC_START + NUM_PAGES * PAGE_SIZE }
+}
+
+//找到包含该页的已分配块的head页号, O(log n)
+//只有块的head有标志, 所以向下按各阶对齐遇到的第一个有标志的页就是head
+unsafe fn block_head(idx: usize) -> Option<usize> {
+	for order in 0..MAX_ORDER {
+		let cand = idx & !((1 << order) - 1);
+		let p = page_desc(cand);
+		if (*p).is_taken() && (*p).get_order() >= order {
+			return Some(cand);
+		}else if (*p).is_taken() || (*p).is_free() {
+			return None;
 		}
+	}
+	None
+}
+
+//能容纳pages个页的最小阶
+pub const fn order_for(pages: usize) -> usize {
+	let mut order = 0;
+	while (1usize << order) < pages {
+		order += 1;
+	}
+	order
+}
+
+unsafe fn free_list_push(idx: usize, order: usize) {
+	let node = page_addr(idx) as *mut FreePages;
+	(*node).prev = null_mut();
+	(*node).next = FREE_LISTS[order];
+	if !FREE_LISTS[order].is_null() {
+		(*FREE_LISTS[order]).prev = node;
+	}
+	FREE_LISTS[order] = node;
+	FREE_COUNT[order] += 1;
+
+	let p = page_desc(idx);
+	(*p).clear();
+	(*p).set_flag(PageBits::Free);
+	(*p).set_order(order);
+}
 
-		ALLOC_START = align_val(HEAP_START + num_pages * size_of::<Page,>(), PAGE_ORDER);
+//从链表中间摘掉一个空闲块, 合并伙伴时用到, O(1)
+unsafe fn free_list_remove(idx: usize, order: usize) {
+	let node = page_addr(idx) as *mut FreePages;
+	if (*node).prev.is_null() {
+		FREE_LISTS[order] = (*node).next;
+	}else{
+		(*(*node).prev).next = (*node).next;
 	}
+	if !(*node).next.is_null() {
+		(*(*node).next).prev = (*node).prev;
+	}
+	FREE_COUNT[order] -= 1;
+	//page-debug: 链表指针(包括邻居改过的prev)写在块头上, 摘下来时改回毒值
+	#[cfg(feature = "page-debug")]
+	debug::repoison_node(idx);
+	(*page_desc(idx)).clear();
 }
 
+unsafe fn free_list_pop(order: usize) -> Option<usize> {
+	let node = FREE_LISTS[order];
+	if node.is_null() {
+		None
+	}else{
+		let idx = page_index(node as usize);
+		free_list_remove(idx, order);
+		Some(idx)
+	}
+}
+
+// ... stack | Page structure | Page structure | ... | 4096 bytes page | 4096 bytes page | ...
+//           ^                                       ^
+//        HEAP_START     真正可分配内存的起始地址: ALLOC_START = ((HEAP_START + num_pages * Page ) + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1)
+// ALLOC_START地址进行了上舍入,对齐到4096页边界
 //
-//参数是申请分配的页个数; usize 动态大小的无符号整数
-pub fn alloc(pages: usize) -> *mut u8 {
-	assert!(pages > 0);
+//伙伴块的对齐是相对于ALLOC_START的页号, 初始时把所有页按最大的对齐块挂到各阶空闲链表
+pub fn init() {
 	unsafe {
-		let num_pages = HEAP_SIZE / PAGE_SIZE;
+		let num_desc = HEAP_SIZE / PAGE_SIZE;
 		let ptr = HEAP_START as *mut Page;
-		//开始搜索连续的页空间; 页数量边界？
-		for i in 0..num_pages - pages {
-			let mut found = false;
-
-			if (*ptr.add(i)).is_free() {
-				found = true;
-				for j in i..i + pages { // 取值i, i+1, i+2 .. i+pages-1
-					if (*ptr.add(j)).is_taken(){
-						found = false;
-						break;
-					}
 
-				}
+		for i in 0..num_desc {
+			(*ptr.add(i)).clear();
+			#[cfg(feature = "page-debug")]
+			{
+				(*ptr.add(i)).alloc_ra = 0;
+				(*ptr.add(i)).free_ra = 0;
 			}
-			
-			if found {
-				for k in i..i + pages -1 {
-					(*ptr.add(k)).set_flag(PageBits::Taken);
-				}
+		}
+
+		ALLOC_START = align_val(HEAP_START + num_desc * size_of::<Page,>(), PAGE_ORDER);
+		NUM_PAGES = (HEAP_START + HEAP_SIZE - ALLOC_START) / PAGE_SIZE;
+
+		for order in 0..MAX_ORDER {
+			FREE_LISTS[order] = null_mut();
+			FREE_COUNT[order] = 0;
+		}
+
+		let mut idx = 0;
+		while idx < NUM_PAGES {
+			let mut order = MAX_ORDER - 1;
+			while idx & ((1 << order) - 1) != 0 || idx + (1 << order) > NUM_PAGES {
+				order -= 1;
+			}
+			free_list_push(idx, order);
+			idx += 1 << order;
+		}
+	}
+}
+
+//伙伴系统所有hart共用, 由PAGE_LOCK保护
+//单页的分配和释放先走本hart的小缓存, 大部分时候不用抢锁
+//缓存里的页在伙伴系统看来是已分配的(order 0), 引用计数为0
+//page-debug时不用缓存, 每次都经过毒值检查
+static mut PAGE_LOCK: Mutex = Mutex::new();
+const PCP_HIGH: usize = 16; //缓存满了就还一批给伙伴系统
+const PCP_BATCH: usize = 8; //空了一次从伙伴系统拿这么多
+
+#[derive(Clone, Copy)]
+struct PageCache {
+	count: usize,
+	pages: [usize; PCP_HIGH], //页号
+}
+
+static mut PAGE_CACHES: [PageCache; MAX_HARTS] = [PageCache { count: 0, pages: [0; PCP_HIGH] }; MAX_HARTS];
+
+//关中断再拿锁, 免得本hart在持锁时被中断进来再拿一次
+unsafe fn page_lock() -> usize {
+	let irq = irq_disable();
+	PAGE_LOCK.spin_lock();
+	irq
+}
 
-				//Last page
-				(*ptr.add(i+pages-1)).set_flag(PageBits::Taken);
-				(*ptr.add(i+pages-1)).set_flag(PageBits::Last);
+unsafe fn page_unlock(irq: usize) {
+	PAGE_LOCK.unlock();
+	irq_restore(irq);
+}
 
-				return (ALLOC_START + PAGE_SIZE * i) as *mut u8;
+#[cfg(not(feature = "page-debug"))]
+unsafe fn pcp_alloc() -> *mut u8 {
+	let irq = irq_disable();
+	let c = &mut PAGE_CACHES[hart_id()];
+	if c.count == 0 {
+		PAGE_LOCK.spin_lock();
+		while c.count < PCP_BATCH {
+			let p = alloc_locked(0, 0);
+			if p.is_null() {
+				break;
 			}
+			let idx = page_index(p as usize);
+			(*page_desc(idx)).ref_dec();
+			c.pages[c.count] = idx;
+			c.count += 1;
+		}
+		PAGE_LOCK.unlock();
+	}
+	let mut ret = null_mut();
+	if c.count > 0 {
+		c.count -= 1;
+		let idx = c.pages[c.count];
+		(*page_desc(idx)).ref_inc();
+		ret = page_addr(idx) as *mut u8;
+	}
+	irq_restore(irq);
+	ret
+}
 
+//idx是引用计数刚降到1、不和别人共享的单页
+#[cfg(not(feature = "page-debug"))]
+unsafe fn pcp_free(idx: usize) {
+	let irq = irq_disable();
+	let c = &mut PAGE_CACHES[hart_id()];
+	(*page_desc(idx)).ref_dec();
+	c.pages[c.count] = idx;
+	c.count += 1;
+	if c.count == PCP_HIGH {
+		PAGE_LOCK.spin_lock();
+		for _ in 0..PCP_BATCH {
+			c.count -= 1;
+			free_block(c.pages[c.count], 0);
 		}
+		PAGE_LOCK.unlock();
+	}
+	irq_restore(irq);
+}
+
+//参数是申请分配的页个数, 会上舍入到2的幂; usize 动态大小的无符号整数
+//从满足阶数的最小非空链表取块, 多余的一半一半地还给低阶链表, O(log n)
+#[inline(never)]
+pub fn alloc(pages: usize) -> *mut u8 {
+	let ra = return_address();
+	alloc_from(pages, ra)
+}
+
+//ra是记录下来的调用者, page-debug用
+fn alloc_from(pages: usize, ra: usize) -> *mut u8 {
+	assert!(pages > 0);
+	let order = order_for(pages);
+	if order >= MAX_ORDER {
+		return null_mut();
+	}
 
+	unsafe {
+		#[cfg(not(feature = "page-debug"))]
+		{
+			if order == 0 {
+				return pcp_alloc();
+			}
+		}
+		let irq = page_lock();
+		let ret = alloc_locked(order, ra);
+		page_unlock(irq);
+		ret
+	}
+}
+
+//调用者持有PAGE_LOCK
+unsafe fn alloc_locked(order: usize, ra: usize) -> *mut u8 {
+	let mut o = order;
+	while o < MAX_ORDER && FREE_LISTS[o].is_null() {
+		o += 1;
+	}
+	if o == MAX_ORDER {
 		// no countiguous allocation was found
-		null_mut()
+		return null_mut();
+	}
+
+	let poisoned = (*page_desc(page_index(FREE_LISTS[o] as usize))).is_poisoned();
+	let idx = free_list_pop(o).unwrap();
+	//拆分: 后一半是伙伴, 挂回低一阶的链表
+	while o > order {
+		o -= 1;
+		free_list_push(idx + (1 << o), o);
+		if poisoned {
+			(*page_desc(idx + (1 << o))).set_flag(PageBits::Poisoned);
+		}
 	}
+
+	#[cfg(feature = "page-debug")]
+	debug::on_alloc(idx, order, poisoned, ra);
+	let _ = (poisoned, ra);
+
+	let p = page_desc(idx);
+	(*p).set_flag(PageBits::Taken);
+	(*p).set_order(order);
+	(*p).ref_inc();
+
+	page_addr(idx) as *mut u8
 }
 
+//释放一个引用, 引用计数降到0才真正释放块
+//释放时与空闲的伙伴逐阶合并, O(log n)
+#[inline(never)]
 pub fn dealloc(ptr: *mut u8) {
+	let ra = return_address();
+	dealloc_from(ptr, ra)
+}
+
+fn dealloc_from(ptr: *mut u8, ra: usize) {
 	assert!(!ptr.is_null());
 
 	unsafe {
-		// 每4096 bytes页分配一个Page structure 即页描述符(0 1 2...) 
-		let addr = HEAP_START + (ptr as usize - ALLOC_START) / PAGE_SIZE;
-		assert!(addr >= HEAP_START && addr < HEAP_START + HEAP_SIZE);
+		let addr = ptr as usize;
+		assert!(addr >= ALLOC_START && addr < ALLOC_START + NUM_PAGES * PAGE_SIZE);
+		assert!(addr & (PAGE_SIZE - 1) == 0);
 
-		let mut p = addr as *mut Page;
+		let idx = page_index(addr);
+		//只有我们持有的单页, 不会有别人同时改它的引用计数
+		#[cfg(not(feature = "page-debug"))]
+		{
+			let p = page_desc(idx);
+			if (*p).is_taken() && (*p).get_order() == 0 && (*p).get_refcnt() == 1 {
+				pcp_free(idx);
+				return;
+			}
+		}
 
-		while (*p).is_taken() && !(*p).is_last() {
-			(*p).clear();
-			p = p.add(1);
+		let irq = page_lock();
+		let p = page_desc(idx);
+		if !(*p).is_taken() || (*p).get_refcnt() == 0 {
+			#[cfg(feature = "page-debug")]
+			debug::report_bad_free(idx, ra);
+			PAGE_LOCK.unlock();
+			panic!("Possible double-free detected! (0x{:x} is not an allocated block)", addr);
 		}
 
-		assert!(
-			(*p).is_last() == true, "Possible double-free detected! (Not taken found before last)"
-		);
+		if (*p).ref_dec() == 0 {
+			free_block(idx, ra);
+		}
+		//否则还有其他页表共享这个块
+		page_unlock(irq);
+	}
+}
+
+//引用计数已经是0的块还给伙伴系统, 调用者持有PAGE_LOCK
+unsafe fn free_block(mut idx: usize, ra: usize) {
+	let p = page_desc(idx);
+	let mut order = (*p).get_order();
+	(*p).clear();
+
+	//page-debug: 刚释放的块填上毒值; 合并后的块只有每一部分都有毒值才算
+	#[cfg(feature = "page-debug")]
+	debug::poison(idx, order, ra);
+	let _ = ra;
+	let mut poisoned = cfg!(feature = "page-debug");
+
+	while order < MAX_ORDER - 1 {
+		let buddy = idx ^ (1 << order);
+		if buddy + (1 << order) > NUM_PAGES {
+			break;
+		}
+		let b = page_desc(buddy);
+		if !(*b).is_free() || (*b).get_order() != order {
+			break;
+		}
+		poisoned = poisoned && (*b).is_poisoned();
+		free_list_remove(buddy, order);
+		idx &= !(1 << order);
+		order += 1;
+	}
 
-		//the last page 最后一页
-		(*p).clear();
+	free_list_push(idx, order);
+	if poisoned {
+		(*page_desc(idx)).set_flag(PageBits::Poisoned);
 	}
 }
 
 // 每个页4096 bytes
 // 参数是要分配的页个数
+#[inline(never)]
 pub fn zalloc(pages: usize) -> *mut u8 {
-	let ret = alloc(pages);
+	let ra = return_address();
+	let ret = alloc_from(pages, ra);
 	if !ret.is_null() {
 		let size = (PAGE_SIZE * pages) / 8;
 		let big_ptr = ret as *mut u64; // 指向8节的指针
@@ -166,10 +471,196 @@ pub fn zalloc(pages: usize) -> *mut u8 {
 	ret
 }
 
+//page-debug: 释放的页填毒值, 重新分配时检查; 记录每页的分配/释放调用者
+//用来找use-after-free和double-free, 代价是每次分配释放都要扫整个块
+#[cfg(feature = "page-debug")]
+mod debug {
+	use core::mem::size_of;
+	use super::{page_addr, page_desc, block_head, FreePages, PAGE_SIZE};
+
+	pub const PAGE_POISON: u64 = 0x6b6b_6b6b_6b6b_6b6b;
+
+	pub unsafe fn poison(idx: usize, order: usize, ra: usize) {
+		for i in idx..idx + (1 << order) {
+			(*page_desc(i)).free_ra = ra;
+			let words = page_addr(i) as *mut u64;
+			for w in 0..PAGE_SIZE / 8 {
+				words.add(w).write_volatile(PAGE_POISON);
+			}
+		}
+	}
+
+	//空闲块摘下链表时调用: 有毒值的块把块头上的FreePages改回毒值, 分配时才不会误报
+	pub unsafe fn repoison_node(idx: usize) {
+		if !(*page_desc(idx)).is_poisoned() {
+			return;
+		}
+		let words = page_addr(idx) as *mut u64;
+		for w in 0..size_of::<FreePages>() / 8 {
+			words.add(w).write_volatile(PAGE_POISON);
+		}
+	}
+
+	//有毒值的块要先检查一遍, 被改过说明释放后还有人在写
+	pub unsafe fn on_alloc(idx: usize, order: usize, poisoned: bool, ra: usize) {
+		for i in idx..idx + (1 << order) {
+			let p = page_desc(i);
+			if poisoned {
+				let words = page_addr(i) as *const u64;
+				for w in 0..PAGE_SIZE / 8 {
+					let val = words.add(w).read_volatile();
+					if val != PAGE_POISON {
+						println!("Page allocator: use-after-free in page 0x{:x}, offset 0x{:x} = {:#x}", page_addr(i), w * 8, val);
+						println!("    page was allocated by ra 0x{:x} and freed by ra 0x{:x}", (*p).alloc_ra, (*p).free_ra);
+						println!("    now being allocated by ra 0x{:x}", ra);
+						panic!("Use-after-free of physical page 0x{:x}", page_addr(i));
+					}
+				}
+			}
+			(*p).alloc_ra = ra;
+		}
+	}
+
+	pub unsafe fn report_bad_free(idx: usize, ra: usize) {
+		let p = page_desc(idx);
+		match block_head(idx) {
+			Some(head) => {
+				println!("Page allocator: free of 0x{:x} by ra 0x{:x}, which is inside the block at 0x{:x}", page_addr(idx), ra, page_addr(head));
+				println!("    block was allocated by ra 0x{:x}", (*page_desc(head)).alloc_ra);
+			},
+			None => {
+				println!("Page allocator: double free of 0x{:x} by ra 0x{:x}", page_addr(idx), ra);
+				println!("    page was allocated by ra 0x{:x} and already freed by ra 0x{:x}", (*p).alloc_ra, (*p).free_ra);
+			},
+		}
+	}
+}
+
+//多一个共享者(例如另一个进程的COW映射), 之后每个共享者各自调用dealloc
+//引用计数按块记, 按页共享的只能是单页块, 多页的先用split_block()拆开
+//不归页分配器管理的地址直接忽略
+pub fn page_ref_inc(addr: usize) {
+	if !is_managed(addr) {
+		return;
+	}
+	unsafe {
+		let irq = page_lock();
+		let head = block_head(page_index(addr));
+		if head.is_none() {
+			PAGE_LOCK.unlock();
+			panic!("Reference to a free page 0x{:x}", addr);
+		}
+		let p = page_desc(head.unwrap());
+		if (*p).get_order() != 0 {
+			PAGE_LOCK.unlock();
+			panic!("Sharing page 0x{:x} of a multi-page block, split it first", addr);
+		}
+		(*p).ref_inc();
+		page_unlock(irq);
+	}
+}
+
+//把没有共享的多页块拆成一个个单页块, 每页有自己的引用计数, 之后可以按页共享、各自释放;
+//比如loader载入的程序镜像, 几个进程共享, 写到哪页才复制哪页
+pub fn split_block(ptr: *mut u8) {
+	let addr = ptr as usize;
+	assert!(is_managed(addr) && addr & (PAGE_SIZE - 1) == 0);
+	unsafe {
+		let irq = page_lock();
+		let idx = page_index(addr);
+		let p = page_desc(idx);
+		if !(*p).is_taken() || (*p).get_refcnt() != 1 {
+			PAGE_LOCK.unlock();
+			panic!("split_block: 0x{:x} is not an unshared allocated block", addr);
+		}
+		let order = (*p).get_order();
+		for i in idx..idx + (1 << order) {
+			let q = page_desc(i);
+			(*q).clear();
+			(*q).set_flag(PageBits::Taken);
+			(*q).ref_inc();
+		}
+		page_unlock(irq);
+	}
+}
+
+//addr是不是单页块; 不归页分配器管理的返回false
+fn is_single_page(addr: usize) -> bool {
+	if !is_managed(addr) {
+		return false;
+	}
+	unsafe {
+		match block_head(page_index(addr)) {
+			Some(head) => (*page_desc(head)).get_order() == 0,
+			None => false,
+		}
+	}
+}
+
+//释放一个共享者对该页所在块的引用
+#[inline(never)]
+pub fn page_ref_dec(addr: usize) {
+	let ra = return_address();
+	if !is_managed(addr) {
+		return;
+	}
+	unsafe {
+		match block_head(page_index(addr)) {
+			Some(head) => dealloc_from(page_addr(head) as *mut u8, ra),
+			None => dealloc_from((addr & !(PAGE_SIZE - 1)) as *mut u8, ra),
+		}
+	}
+}
+
+//包含该页的块的引用计数, 空闲页或不归分配器管理的地址返回0
+pub fn page_ref_count(addr: usize) -> usize {
+	if !is_managed(addr) {
+		return 0;
+	}
+	unsafe {
+		match block_head(page_index(addr)) {
+			Some(head) => (*page_desc(head)).get_refcnt(),
+			None => 0,
+		}
+	}
+}
+
+//包含该地址的已分配块的起始地址
+pub fn block_of(addr: usize) -> Option<usize> {
+	if !is_managed(addr) {
+		return None;
+	}
+	unsafe { block_head(page_index(addr)).map(|head| page_addr(head)) }
+}
+
+//某一阶的空闲块个数
+pub fn free_blocks(order: usize) -> usize {
+	assert!(order < MAX_ORDER);
+	unsafe { FREE_COUNT[order] }
+}
+
+//所有空闲页数, 包括各hart缓存着的
+pub fn free_pages() -> usize {
+	let mut num = 0;
+	for order in 0..MAX_ORDER {
+		num += free_blocks(order) << order;
+	}
+	num + cached_pages()
+}
+
+//各hart缓存着的单页: 在页描述符里还是Taken, 但引用计数是0, 算空闲页
+fn cached_pages() -> usize {
+	unsafe { PAGE_CACHES.iter().map(|c| c.count).sum() }
+}
+
+pub fn total_pages() -> usize {
+	unsafe { NUM_PAGES }
+}
+
 pub fn print_page_allocations() {
 	unsafe {
-		let num_pages = HEAP_SIZE / PAGE_SIZE;
-		let mut beg = HEAP_START as *const Page;
+		let num_pages = NUM_PAGES;
+		let beg = HEAP_START as *const Page;
 		let end = beg.add(num_pages);
 		let alloc_beg = ALLOC_START;
 		let alloc_end = ALLOC_START + num_pages * PAGE_SIZE;
@@ -177,29 +668,35 @@ pub fn print_page_allocations() {
 		println!("PAGE ALLOCATION TABLE\nPage Descriptors:{:p} -> {:p}\nPHYS:            0x{:x} -> 0x{:x}", beg, end, alloc_beg, alloc_end);
 		println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
 		let mut num = 0;
-		while beg < end {
-			if (*beg).is_taken() {
-				let start = beg as usize; //页描述符地址, (beg - HEAP_START)为相隔的页描述符个数
-				let memaddr = ALLOC_START + (start - HEAP_START) * PAGE_SIZE;
+		let mut idx = 0;
+		//只看每个块的head, 按块大小跳过
+		while idx < num_pages {
+			let p = page_desc(idx);
+			if (*p).is_taken() && (*p).get_refcnt() == 0 {
+				//缓存在某个hart上的页, 算在Free里
+				idx += 1;
+			}else if (*p).is_taken() {
+				let pages = 1 << (*p).get_order();
+				let memaddr = page_addr(idx);
 				print!("0x{:x} => ", memaddr);
-				loop {
-					num +=1;
-					if (*beg).is_last() {
-						let end = beg as usize;
-						let memaddr = ALLOC_START + (end - HEAP_START) * PAGE_SIZE + PAGE_SIZE - 1;
-						print!("0x{:x}: {:>3} page(s)", memaddr, (end - start + 1));
-						println!(".");
-						break
-
-					}
-					beg = beg.add(1);
-				}
+				print!("0x{:x}: {:>3} page(s)", memaddr + pages * PAGE_SIZE - 1, pages);
+				println!(".");
+				num += pages;
+				idx += pages;
+			}else if (*p).is_free() {
+				idx += 1 << (*p).get_order();
+			}else{
+				idx += 1;
 			}
-			beg = beg.add(1);
 		}
 		println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
 		println!("Allocated: {:>6} pages ({:>10} bytes).", num, num * PAGE_SIZE);
-		println!("Free     : {:>6} pages ({:>10} bytes).", num_pages - num, (num_pages - num) * PAGE_SIZE);
+		println!("Free     : {:>6} pages ({:>10} bytes).", free_pages(), free_pages() * PAGE_SIZE);
+		println!("  cached : {:>6} pages on the harts, counted in Free.", cached_pages());
+		for order in 0..MAX_ORDER {
+			print!("{:>5}", free_blocks(order));
+		}
+		println!("  <- free blocks of order 0..{}", MAX_ORDER - 1);
 		println!();
 
 	}
@@ -219,6 +716,8 @@ pub enum EntryBits {
 	Global = 1 << 5,
 	Access = 1 << 6,
 	Dirty = 1 << 7,
+	//RSW软件保留位: 写时复制, 该页表项只读, 写入时由缺页处理复制
+	Cow = 1 << 8,
 
 	ReadWrite = 1 << 1 | 1 << 2,
 	ReadExecute = 1 << 1 | 1 << 3,
@@ -244,6 +743,85 @@ impl Table {
 	pub fn len() -> usize {
 		512
 	}
+
+	//遍历所有有效叶子(包括大页), self必须是根页表
+	pub fn iter(&self) -> TableIter {
+		let mut tables = [null_mut() as *const Table; MAX_LEVELS];
+		tables[top_level()] = self as *const Table;
+		TableIter {
+			tables,
+			index: [0; MAX_LEVELS],
+			level: top_level(),
+			top: top_level(),
+			_root: PhantomData,
+		}
+	}
+}
+
+//一个有效叶子映射
+#[derive(Clone, Copy)]
+pub struct Mapping {
+	pub vaddr: usize,
+	pub paddr: usize,
+	pub flags: i64, //页表项的低10位
+	pub level: usize,
+}
+
+impl Mapping {
+	pub fn size(&self) -> usize {
+		level_size(self.level)
+	}
+}
+
+//深度优先的页表遍历, 不用递归也不用分配内存
+//tables[l]是正在看的l级页表, index[l]是其中的下标
+pub struct TableIter<'a> {
+	tables: [*const Table; MAX_LEVELS],
+	index: [usize; MAX_LEVELS],
+	level: usize,
+	top: usize,
+	_root: PhantomData<&'a Table>,
+}
+
+impl<'a> Iterator for TableIter<'a> {
+	type Item = Mapping;
+
+	fn next(&mut self) -> Option<Mapping> {
+		loop {
+			let level = self.level;
+			if self.index[level] >= Table::len() {
+				//这一级看完了, 回到上一级
+				if level == self.top {
+					return None;
+				}
+				self.level += 1;
+				self.index[self.level] += 1;
+				continue;
+			}
+
+			let entry = unsafe { &(*self.tables[level]).entries[self.index[level]] };
+			if entry.is_invalid() {
+				self.index[level] += 1;
+			}else if entry.is_leaf() || level == 0 {
+				let mut vaddr = 0;
+				for l in level..=self.top {
+					vaddr |= self.index[l] << (12 + l * 9);
+				}
+				let vaddr = sign_extend(vaddr);
+				self.index[level] += 1;
+				return Some(Mapping {
+					vaddr,
+					paddr: ((entry.get_entry() & !0x3ff) << 2) as usize,
+					flags: entry.get_entry() & 0x3ff,
+					level,
+				});
+			}else{
+				self.level -= 1;
+				self.tables[self.level] = ((entry.get_entry() & !0x3ff) << 2) as *const Table;
+				self.index[self.level] = 0;
+			}
+		}
+	}
 }
 
 pub struct Entry {
@@ -276,6 +854,16 @@ impl Entry {
 		!self.is_leaf()
 	}
 
+	//全局页表项: 内核那一半, 所有地址空间共享
+	pub fn is_global(&self) -> bool {
+		self.get_entry() & EntryBits::Global.val() != 0
+	}
+
+	//叶子指向的物理页, 或者下一级页表的物理地址
+	pub fn get_addr(&self) -> usize {
+		((self.get_entry() & !0x3ff) << 2) as usize
+	}
+
 }
 
 // Page Table Entry:
@@ -291,103 +879,498 @@ impl Entry {
 // | PPN[2] | PPN[1] | PPN[0] | page offset |
 //    26       9        9          12
 
+// Sv48多一级:
+// | VPN[3] | VPN[2] | VPN[1] | VPN[0] | page offset |
+//    9        9        9        9          12
+
+// level级页表项映射的页大小: 0 -> 4K页, 1 -> 2M页, 2 -> 1G页, 3 -> 512G页
+pub const fn level_size(level: usize) -> usize {
+	1 << (12 + level * 9)
+}
+
+//第level级的VPN, 掩码 0x1ff = 0b1_1111_1111 (9位）
+pub const fn vpn(vaddr: usize, level: usize) -> usize {
+	(vaddr >> (12 + level * 9)) & 0x1ff
+}
+
+//分页级数: Sv39 = 3级, Sv48 = 4级, 启动时由set_mode()选定
+pub const MAX_LEVELS: usize = 4;
+static mut PAGING_LEVELS: usize = 3;
+
+pub fn levels() -> usize {
+	unsafe { PAGING_LEVELS }
+}
+
+//根页表的级数
+pub fn top_level() -> usize {
+	levels() - 1
+}
+
+pub fn satp_mode() -> SatpMode {
+	if levels() == 4 {
+		SatpMode::Sv48
+	}else{
+		SatpMode::Sv39
+	}
+}
+
+//必须在创建任何页表之前调用
+pub fn set_mode(mode: SatpMode) {
+	unsafe {
+		PAGING_LEVELS = match mode {
+			SatpMode::Sv48 => 4,
+			SatpMode::Sv39 => 3,
+			SatpMode::Off => panic!("Paging mode must be Sv39 or Sv48"),
+		};
+	}
+}
+
+//试写satp用的根页表: 前4项是根级的恒等大页(Sv39每项1G, Sv48每项512G), 盖住内存和MMIO;
+//S态写satp立刻生效(M态不受影响), 用这张表即使hart接受了也能接着取指访存. 用完dealloc
+pub fn probe_table(mode: SatpMode) -> *mut u8 {
+	let table = zalloc(1);
+	let shift = match mode {
+		SatpMode::Sv48 => 39,
+		_ => 30,
+	};
+	let bits = EntryBits::Valid.val() | EntryBits::ReadWriteExecute.val() | EntryBits::Global.val()
+		| EntryBits::Access.val() | EntryBits::Dirty.val();
+	let t = unsafe { (table as *mut Table).as_mut().unwrap() };
+	for i in 0..4 {
+		t.entries[i].set_entry((((i << shift) >> 2) as i64) | bits);
+	}
+	table
+}
+
+//看hart是否支持want模式: satp是WARL, 不支持的模式写进去会被忽略; 不支持就退回Sv39
+pub fn probe_mode(want: SatpMode) -> SatpMode {
+	let table = probe_table(want);
+	let old = satp_read();
+	satp_write(build_satp(want, 0, table as usize));
+	let got = satp_read() >> 60;
+	satp_write(old);
+	satp_fence_all();
+	dealloc(table);
+
+	if got == want as usize {
+		want
+	}else{
+		println!("satp rejected paging mode {}, falling back to Sv39", want as usize);
+		SatpMode::Sv39
+	}
+}
+
+//虚拟地址的最高有效位(Sv39第38位, Sv48第47位)符号扩展到高位
+fn sign_extend(vaddr: usize) -> usize {
+	let bits = 12 + levels() * 9;
+	if vaddr & (1 << (bits - 1)) != 0 {
+		vaddr | (!0 << bits)
+	}else{
+		vaddr
+	}
+}
+
 // level: 0 -> 4K页, 1 -> 2M页, 2 -> 1G页 
+// 大页要求vaddr和paddr都按页大小对齐
+// bits带有EntryBits::Cow时映射成只读的写时复制页, 写入时由cow_fault()解决
 pub fn map(root: &mut Table, vaddr: usize, paddr: usize, bits: i64, level: usize) {
 	//    0xe置位了RWX, bits会在最后页中使用，需要保证有效 
 	assert!(bits & 0xe !=0);
+	assert!(level < levels());
+	//4K页的页内偏移直接忽略, 大页必须对齐
+	assert!(level == 0 || (vaddr & (level_size(level) - 1) == 0 && paddr & (level_size(level) - 1) == 0),
+		"Unaligned level {} mapping 0x{:x} -> 0x{:x}", level, vaddr, paddr);
+	let bits = if bits & EntryBits::Cow.val() != 0 {
+		bits & !EntryBits::Write.val()
+	}else{
+		bits
+	};
 
-	//掩码 0x1ff = 0b1_1111_1111 (9位）
-	let vpn = [
-		(vaddr >> 12) & 0x1ff, //VPN[0]
-		(vaddr >> 21) & 0x1ff, //VPN[1]
-		(vaddr >> 30) & 0x1ff, //VPN[2]
-	];
-
-	let ppn = [
-		(paddr >> 12) & 0x1ff,
-		(paddr >> 21) & 0x1ff,
-		(paddr >> 30) & 0x3ff_fff, //26 bits
-	];
-	// 而"page offset"不用管，因为会直接从虚拟地址复制到物理地址
+	// "page offset"不用管，因为会直接从虚拟地址复制到物理地址
+	let top = top_level();
 
 	//页表项定位
-	let mut v = &mut root.entries[vpn[2]];
+	let mut v = &mut root.entries[vpn(vaddr, top)];
 
-	//rev()只是反转顺序 1,0
-	for i in (level..2).rev() {
+	//rev()只是反转顺序, 从根往下走到level级
+	for i in (level..top).rev() {
 		if !v.is_valid() {
 			let page = zalloc(1); //创建新页表
 			// 分配得到4096页对齐的物理地址，为了匹配上64位的页表条目, 右移2位
-			v.set_entry((page as i64 >> 2) | EntryBits::Valid.val());
+			//内核的全局映射, 中间的页表项也标上G, 表示这张表是所有地址空间共享的
+			v.set_entry((page as i64 >> 2) | (bits & EntryBits::Global.val()) | EntryBits::Valid.val());
+		}else if v.is_global() && bits & EntryBits::Global.val() == 0 {
+			//往共享的内核页表里加用户映射: 先复制一份私有的, 不能改到别的地址空间
+			if v.is_leaf() {
+				//内核的大页拆成一张新表, 本来就是私有的
+				split_leaf(v, i + 1);
+				v.set_entry(v.get_entry() & !EntryBits::Global.val());
+			}else{
+				let page = zalloc(1);
+				unsafe {
+					core::ptr::copy_nonoverlapping(v.get_addr() as *const u8, page, PAGE_SIZE);
+				}
+				v.set_entry((page as i64 >> 2) | EntryBits::Valid.val());
+			}
 		}
+		assert!(v.is_branch(), "0x{:x} is already covered by a level {} huge page", vaddr, i + 1);
 		// 0x3ff = 0b 11_1111_1111, 只保留页表条目中的PPN[2|1|0], 并左移2位变成物理地址形式
 		//该地址值 作为一个Entry的指针
 		let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
 		v = unsafe {
 			//注意 从值变为另外一个Entry指针
-			entry.add(vpn[i]).as_mut().unwrap()
+			entry.add(vpn(vaddr, i)).as_mut().unwrap()
 		};
 	}
 
-	//此时v应该是VPN[0]的Entry
-	//页表条目和物理地址的转移位数不一样，少2
-	let entry = (ppn[2] << 28) as i64 |
-		    (ppn[1] << 19) as i64 |
-		    (ppn[0] << 10) as i64 |
+	//此时v应该是VPN[level]的Entry; 已经是下级页表的话不能直接覆盖成大页
+	assert!(v.is_invalid() || v.is_leaf(), "0x{:x} already has a page table at level {}", vaddr, level);
+	//页表条目和物理地址的转移位数不一样，少2; PPN一共44位
+	let entry = ((paddr >> 12) << 10) as i64 |
 		    bits | EntryBits::Valid.val();
 
 	v.set_entry(entry);
 }
 
+//内核页表模板: 内核的映射都是全局(G)、非用户(U=0)的, 每个进程的根页表都装上同一份
+static mut KERNEL_ROOT: *mut Table = null_mut();
+
+pub fn set_kernel_root(root: *mut Table) {
+	unsafe {
+		KERNEL_ROOT = root;
+	}
+}
+
+pub fn kernel_root() -> *const Table {
+	unsafe { KERNEL_ROOT as *const Table }
+}
+
+//把内核那一半装进进程的根页表: 直接复制根页表项, 下面的页表是共享的
+//之后内核模板新加的根页表项不会自动出现在已有的进程里
+pub fn install_kernel(root: &mut Table) {
+	let kernel = kernel_root();
+	assert!(!kernel.is_null(), "Kernel page table is not built yet");
+	for i in 0..Table::len() {
+		let entry = unsafe { (*kernel).entries[i].get_entry() };
+		if entry & EntryBits::Valid.val() != 0 {
+			assert!(root.entries[i].is_invalid());
+			root.entries[i].set_entry(entry);
+		}
+	}
+}
+
+//映射一段范围, 每一步都挑vaddr和paddr同时对齐、且剩余长度放得下的最大页
+pub fn map_range(root: &mut Table, vaddr: usize, paddr: usize, len: usize, bits: i64) {
+	let mut vaddr = vaddr & !(PAGE_SIZE - 1);
+	let mut paddr = paddr & !(PAGE_SIZE - 1);
+	let end = align_val(vaddr + len, PAGE_ORDER);
+
+	while vaddr < end {
+		let mut level = 2;
+		while level > 0 {
+			let size = level_size(level);
+			if vaddr & (size - 1) == 0 && paddr & (size - 1) == 0 && end - vaddr >= size {
+				break;
+			}
+			level -= 1;
+		}
+		map(root, vaddr, paddr, bits, level);
+		vaddr += level_size(level);
+		paddr += level_size(level);
+	}
+}
+
+//递归释放table下面的各级页表; level是table自己的级数
+//叶子(包括大页)指向的是数据页, 不是页表, 不能交给dealloc
+fn free_tables(table: &mut Table, level: usize) {
+	if level == 0 {
+		return;
+	}
+	for i in 0..Table::len() {
+		let ref entry = table.entries[i];
+		//全局的是内核共享的页表, 不归这个地址空间
+		if entry.is_valid() && entry.is_branch() && !entry.is_global() {
+			let memaddr = (entry.get_entry() & !0x3ff) << 2;
+			let child = unsafe { (memaddr as *mut Table).as_mut().unwrap() };
+			free_tables(child, level - 1);
+			dealloc(memaddr as *mut u8);
+		}
+	}
+}
+
 //只清页表，不会清root根页表，应为它常嵌在进程结构中
 //不清页内存
 pub fn unmap(root: &mut Table) {
-	for lv2 in 0..Table::len(){
-		let ref entry_lv2 = root.entries[lv2];
-		if entry_lv2.is_valid() && entry_lv2.is_branch() {
-			let memaddr_lv1 = (entry_lv2.get_entry() & !0x3ff) << 2;
-			// Make table_lv1 a mutable reference instead of a pointer.
-			let table_lv1 = unsafe { (memaddr_lv1 as *mut Table).as_mut().unwrap() };
-			for lv1 in 0..Table::len() {
-				let ref entry_lv1 = table_lv1.entries[lv1];
-				if entry_lv1.is_valid() && entry_lv1.is_branch()
-				{
-					let memaddr_lv0 = (entry_lv1.get_entry() & !0x3ff) << 2;
-					dealloc(memaddr_lv0 as *mut u8); //请VPN[0]表
-				}
+	free_tables(root, top_level());
+	// page页不要清掉吗？
+}
+
+//范围操作: 清掉叶子, 或改写叶子的权限位
+enum RangeOp {
+	Unmap,
+	Protect(i64),
+}
+
+//把一个大页叶子拆成下一级的512个叶子, 权限不变
+fn split_leaf(entry: &mut Entry, level: usize) {
+	assert!(level > 0 && entry.is_leaf());
+	let paddr = ((entry.get_entry() & !0x3ff) << 2) as usize;
+	let bits = entry.get_entry() & 0x3ff;
+	let table = zalloc(1) as *mut Table;
+	//调用者(map/unmap_range等)没有失败的返回路径, 这里分不到页只能停下
+	assert!(!table.is_null(), "split_leaf: out of memory splitting the level {} huge page at 0x{:x}", level, paddr);
+	for i in 0..Table::len() {
+		let child = paddr + i * level_size(level - 1);
+		unsafe {
+			(*table).entries[i].set_entry((child as i64 >> 2) | bits);
+		}
+	}
+	entry.set_entry((table as i64 >> 2) | (bits & EntryBits::Global.val()) | EntryBits::Valid.val());
+}
+
+//在level级的table里处理[start, end)范围, base是该表覆盖的起始虚拟地址
+//部分覆盖的大页先拆开; 下级页表变空就释放. 返回该表是否已经全空
+//global为false时跳过内核共享的全局映射, 进程的操作不能改到内核那一半
+fn range_walk(table: &mut Table, level: usize, base: usize, start: usize, end: usize, op: &RangeOp, global: bool) -> bool {
+	let size = level_size(level);
+	for i in 0..Table::len() {
+		let lo = base + i * size;
+		let hi = lo + size;
+		if hi <= start || lo >= end {
+			continue;
+		}
+		let covered = start <= lo && hi <= end;
+		let entry = &mut table.entries[i];
+		if entry.is_invalid() || (entry.is_global() && !global) {
+			continue;
+		}
+		if entry.is_leaf() && !covered {
+			split_leaf(entry, level);
+		}
+		if entry.is_leaf() {
+			match op {
+				RangeOp::Unmap => entry.set_entry(0),
+				RangeOp::Protect(bits) => {
+					let mut bits = *bits & 0x1e;
+					//写时复制页保持只读, 写入时再由缺页处理给写权限
+					if entry.get_entry() & EntryBits::Cow.val() != 0 {
+						bits &= !EntryBits::Write.val();
+					}
+					entry.set_entry(entry.get_entry() & !0x1e | bits);
+				},
+			}
+		}else{
+			let memaddr = (entry.get_entry() & !0x3ff) << 2;
+			let child = unsafe { (memaddr as *mut Table).as_mut().unwrap() };
+			if range_walk(child, level - 1, lo, start, end, op, global) {
+				dealloc(memaddr as *mut u8);
+				entry.set_entry(0);
 			}
-			dealloc(memaddr_lv1 as *mut u8); //清VPN[1]表
 		}
 	}
-	// page页不要清掉吗？
+	table.entries.iter().all(|e| e.is_invalid())
 }
 
-pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
-	let vpn = [
-		(vaddr >> 12) & 0x1ff,
-		(vaddr >> 21) & 0x1ff,
-		(vaddr >> 30) & 0x1ff,
-		];
-
-	let mut v = &root.entries[vpn[2]];
-	for i in (0..=2).rev() {
+//刷新[vaddr, vaddr+len)在该ASID下的TLB; 范围大了就直接刷整个ASID.
+//内核页表里的是全局页, 带ASID的sfence.vma刷不掉, 要按所有ASID刷, 别的hart也要刷;
+//sbi::init()之前只有hart 0在用内核页表, 而且可能还在M态, 只刷本地
+fn fence_range(vaddr: usize, len: usize, asid: usize, global: bool) {
+	let pages = len / PAGE_SIZE;
+	if global {
+		if pages > 64 {
+			satp_fence_all();
+		}else{
+			for i in 0..pages {
+				satp_fence_global(vaddr + i * PAGE_SIZE);
+			}
+		}
+		if sbi::ready() {
+			if let Err(e) = sbi::remote_sfence_vma(0, vaddr, len) {
+				panic!("Remote sfence.vma failed: {}", e);
+			}
+		}
+	}else if pages > 64 {
+		satp_fence_asid(asid);
+	}else{
+		for i in 0..pages {
+			satp_fence(vaddr + i * PAGE_SIZE, asid);
+		}
+	}
+}
+
+//取消[vaddr, vaddr+len)的映射, 类似munmap; 只清页表项, 不释放数据页
+//root根页表自己不会被释放
+pub fn unmap_range(root: &mut Table, vaddr: usize, len: usize, asid: usize) {
+	let start = vaddr & !(PAGE_SIZE - 1);
+	let end = align_val(vaddr + len, PAGE_ORDER);
+	let global = root as *const Table == kernel_root();
+	range_walk(root, top_level(), 0, start, end, &RangeOp::Unmap, global);
+	fence_range(start, end - start, asid, global);
+}
+
+//改写[vaddr, vaddr+len)已映射页的RWXU权限位, 类似mprotect
+pub fn protect_range(root: &mut Table, vaddr: usize, len: usize, bits: i64, asid: usize) {
+	assert!(bits & 0xe != 0);
+	let start = vaddr & !(PAGE_SIZE - 1);
+	let end = align_val(vaddr + len, PAGE_ORDER);
+	let global = root as *const Table == kernel_root();
+	range_walk(root, top_level(), 0, start, end, &RangeOp::Protect(bits), global);
+	fence_range(start, end - start, asid, global);
+}
+
+//找到映射vaddr的叶子, 返回的vaddr/paddr是该页(可能是大页)的起始地址
+pub fn lookup(root: &Table, vaddr: usize) -> Option<Mapping> {
+	let top = top_level();
+	let mut v = &root.entries[vpn(vaddr, top)];
+	for i in (0..=top).rev() {
 		if v.is_invalid() {
 			break;
 		}else if v.is_leaf() {
 			// offset mask掩码, 0b 1_1111_1111  
-			let off_mask = (1 << (12 + i * 9)) - 1;
-			//保留虚拟地址末尾处的位
-			let vaddr_pgoff = vaddr & off_mask;
-			let addr = ((v.get_entry() << 2) as usize) & !off_mask;
-			return Some(addr | vaddr_pgoff);
+			let off_mask = level_size(i) - 1;
+			return Some(Mapping {
+				vaddr: vaddr & !off_mask,
+				paddr: v.get_addr() & !off_mask,
+				flags: v.get_entry() & 0x3ff,
+				level: i,
+			});
+		}else if i == 0 {
+			//最后一级不可能再是页表
+			break;
 		}
 
 		let entry = ((v.get_entry() & !0x3ff) << 2) as *const Entry;
 		v = unsafe { 
-			// 假如i=0时会是叶子，不会到达这里
-			entry.add(vpn[i - 1]).as_ref().unwrap()
+			entry.add(vpn(vaddr, i - 1)).as_ref().unwrap()
 		};
 	}
 	None
 }
 
+//地址翻译, 同时返回映射这个地址的页大小(4K/2M/1G/512G)
+pub fn translate(root: &Table, vaddr: usize) -> Option<(usize, usize)> {
+	//保留虚拟地址末尾处的页内偏移
+	lookup(root, vaddr).map(|m| (m.paddr | (vaddr & (m.size() - 1)), m.size()))
+}
+
+pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
+	translate(root, vaddr).map(|(paddr, _)| paddr)
+}
+
+//页表项标志的文本形式, 例如 "vrwxu-ad-"
+fn print_flags(flags: i64) {
+	let names = ['v', 'r', 'w', 'x', 'u', 'g', 'a', 'd', 'c'];
+	for (i, c) in names.iter().enumerate() {
+		if flags & (1 << i) != 0 {
+			print!("{}", c);
+		}else{
+			print!("-");
+		}
+	}
+}
+
+//打印页表的所有映射, 虚拟地址和物理地址都连续、标志相同的叶子合并成一行
+//每行格式: vstart-vend => pstart flags size page(s)
+pub fn print_mappings(root: &Table) {
+	let mut run: Option<(Mapping, usize)> = None;
+	let mut total = 0;
+	let flush = |run: &Option<(Mapping, usize)>| {
+		if let Some((m, len)) = run {
+			print!("0x{:016x}-0x{:016x} => 0x{:016x} ", m.vaddr, m.vaddr + *len - 1, m.paddr);
+			print_flags(m.flags);
+			println!(" {:>6} page(s)", *len / PAGE_SIZE);
+		}
+	};
+
+	println!("~~~~~~~~~~~~~~~~~~~~ mappings of table {:p} ~~~~~~~~~~~~~~~~~~~~", root);
+	for m in root.iter() {
+		total += m.size();
+		if let Some((start, len)) = run.as_mut() {
+			if start.vaddr + *len == m.vaddr && start.paddr + *len == m.paddr && start.flags == m.flags {
+				*len += m.size();
+				continue;
+			}
+		}
+		flush(&run);
+		run = Some((m, m.size()));
+	}
+	flush(&run);
+	println!("Mapped: {} page(s) ({} bytes)", total / PAGE_SIZE, total);
+	println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+}
+
+//找到vaddr对应的4K叶子页表项
+fn leaf_entry(root: &mut Table, vaddr: usize) -> Option<&mut Entry> {
+	let top = top_level();
+	let mut v = &mut root.entries[vpn(vaddr, top)];
+	for i in (0..top).rev() {
+		if v.is_invalid() || v.is_leaf() {
+			//大页不做写时复制
+			return None;
+		}
+		let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
+		v = unsafe { entry.add(vpn(vaddr, i)).as_mut().unwrap() };
+	}
+	if v.is_valid() {
+		Some(v)
+	}else{
+		None
+	}
+}
+
+//写时复制缺页的处理结果
+#[derive(Clone, Copy)]
+pub enum CowFault {
+	//不是写时复制页, 是真正的非法访问
+	NotCow,
+	//只剩这一个映射, 直接恢复可写
+	Reused,
+	//复制到了新页: (旧物理页, 新物理页), 旧页的引用已经释放
+	Copied(usize, usize),
+	//要复制但分不到新页, 映射没动
+	NoMemory,
+}
+
+impl CowFault {
+	//可以重新执行那条store了
+	pub fn handled(&self) -> bool {
+		match self {
+			CowFault::Reused | CowFault::Copied(..) => true,
+			_ => false,
+		}
+	}
+}
+
+//store page fault (cause 15)时调用; 调用者负责sfence.vma
+pub fn cow_fault(root: &mut Table, vaddr: usize) -> CowFault {
+	let v = match leaf_entry(root, vaddr) {
+		Some(v) => v,
+		None => return CowFault::NotCow,
+	};
+	let bits = v.get_entry() & 0x3ff;
+	if bits & EntryBits::Cow.val() == 0 {
+		return CowFault::NotCow;
+	}
+	let new_bits = (bits & !EntryBits::Cow.val()) | EntryBits::Write.val();
+	let old = ((v.get_entry() & !0x3ff) << 2) as usize;
+	//引用计数按块记, 多页块里的一页看不出自己有没有被共享
+	assert!(!is_managed(old) || is_single_page(old), "COW page 0x{:x} is inside a multi-page block", old);
+
+	//不归页分配器管理的页(内核镜像里的)计数是0, 总是复制
+	if page_ref_count(old) == 1 {
+		v.set_entry(v.get_entry() & !0x3ff | new_bits);
+		return CowFault::Reused;
+	}
+
+	let new = alloc(1);
+	if new.is_null() {
+		return CowFault::NoMemory;
+	}
+	unsafe {
+		core::ptr::copy_nonoverlapping(old as *const u8, new, PAGE_SIZE);
+	}
+	v.set_entry((new as i64 >> 2) | new_bits);
+	page_ref_dec(old);
+
+	CowFault::Copied(old, new as usize)
+}
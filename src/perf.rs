@@ -0,0 +1,122 @@
+// perf.rs
+// 硬件性能计数器
+//
+// mcycle/minstret一直在计数, mhpmcounter3-6计什么由mhpmevent3-6配置(事件号由实现定义);
+// 配置只能在M态做(S态通过SBI请machine.rs写), M态打开mcounteren后S态通过cycle/instret/hpmcounterN读;
+// 在SBI固件下mcounteren归固件管, 不一定放开了hpmcounter, 这时只计cycle/instret;
+// 每个hart记一份上次切换时的快照, sched::schedule()切换进程时把差值记到刚下CPU的进程头上
+
+use crate::cpu::{hart_id, MAX_HARTS};
+use crate::csr::{self, Counteren};
+use crate::sbi;
+
+//可编程计数器mhpmcounter3开始的个数
+pub const NUM_HPM: usize = 4;
+
+//一组计数器的值; 也是perf系统调用拷给用户的格式
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Counters {
+	pub cycle:   usize,
+	pub instret: usize,
+	pub hpm:     [usize; NUM_HPM],
+}
+
+impl Counters {
+	pub const fn zero() -> Self {
+		Counters { cycle: 0, instret: 0, hpm: [0; NUM_HPM] }
+	}
+
+	//读本hart现在的计数
+	pub fn read() -> Self {
+		let mut c = Counters::zero();
+		c.cycle = csr::cycle::read();
+		c.instret = csr::instret::read();
+		if unsafe { HPM_READABLE } {
+			c.hpm = [
+				csr::hpmcounter3::read(),
+				csr::hpmcounter4::read(),
+				csr::hpmcounter5::read(),
+				csr::hpmcounter6::read(),
+			];
+		}
+		c
+	}
+
+	//self - earlier, 计数器回绕也没关系
+	pub fn since(&self, earlier: &Counters) -> Self {
+		let mut d = Counters::zero();
+		d.cycle = self.cycle.wrapping_sub(earlier.cycle);
+		d.instret = self.instret.wrapping_sub(earlier.instret);
+		for i in 0..NUM_HPM {
+			d.hpm[i] = self.hpm[i].wrapping_sub(earlier.hpm[i]);
+		}
+		d
+	}
+
+	pub fn add(&mut self, d: &Counters) {
+		self.cycle = self.cycle.wrapping_add(d.cycle);
+		self.instret = self.instret.wrapping_add(d.instret);
+		for i in 0..NUM_HPM {
+			self.hpm[i] = self.hpm[i].wrapping_add(d.hpm[i]);
+		}
+	}
+}
+
+//每个hart上次切换时的计数和当时换上去的进程, pid为0表示没有进程在跑
+static mut LAST: [Counters; MAX_HARTS] = [Counters::zero(); MAX_HARTS];
+static mut CURRENT: [u16; MAX_HARTS] = [0; MAX_HARTS];
+//S态能不能读hpmcounterN, 不能的话读了是非法指令
+static mut HPM_READABLE: bool = false;
+
+//每个hart启动时调用: 让U态也能直接读cycle/time/instret(和hpmcounter), 并记下起始快照
+//hpm: M态的mcounteren是否放开了hpmcounter(machine::init_hart()会放开)
+pub fn init_hart(hpm: bool) {
+	let mut en = Counteren::new().with_cy(true).with_tm(true).with_ir(true);
+	if hpm {
+		for i in 0..NUM_HPM {
+			en = en.with_hpm(3 + i, true);
+		}
+	}
+	csr::scounteren::write(en);
+	unsafe {
+		HPM_READABLE = hpm;
+		let hart = hart_id();
+		LAST[hart] = Counters::read();
+		CURRENT[hart] = 0;
+	}
+}
+
+//让本hart第i个可编程计数器(0 - NUM_HPM-1)计event事件, 并清零; 事件号见具体CPU的手册.
+//mhpmevent只有M态能写, 通过machine.rs的SBI扩展设置; 其他固件下返回SBI_ERR_NOT_SUPPORTED
+pub fn set_event(i: usize, event: usize) -> Result<(), isize> {
+	assert!(i < NUM_HPM, "perf: no hpm counter {}", i);
+	sbi::sos_set_hpm_event(i, event)?;
+	//快照里的旧值作废
+	unsafe {
+		LAST[hart_id()].hpm[i] = 0;
+	}
+	Ok(())
+}
+
+//本hart要换上next进程时调用, 返回上一个进程的pid和它这次用掉的计数
+pub fn switch(hart: usize, next: u16) -> (u16, Counters) {
+	let now = Counters::read();
+	unsafe {
+		let d = now.since(&LAST[hart]);
+		let prev = CURRENT[hart];
+		LAST[hart] = now;
+		CURRENT[hart] = next;
+		(prev, d)
+	}
+}
+
+//本hart上正在跑的进程从上次切换到现在用掉的计数(还没记进Process.perf的部分)
+pub fn pending(hart: usize, pid: u16) -> Counters {
+	unsafe {
+		if CURRENT[hart] != pid {
+			return Counters::zero();
+		}
+		Counters::read().since(&LAST[hart])
+	}
+}
@@ -1,13 +1,15 @@
 use crate::uart;
 
 //通过MMIO地址对平台级中断控制器PLIC的寄存器进行设置
+//使能/阀值/claim每个context一份, hart N的M态是context 2N, S态是2N+1;
+//外部中断委派给了S态, 这里用hart 0的S态context(1)
 const PLIC_PRIORITY: usize = 0x0c00_0000;
 const PLIC_PENDING: usize = 0x0c00_1000;
-const PLIC_INT_ENABLE: usize = 0x0c00_2000;
-const PLIC_THRESHOLD: usize = 0x0c20_0000;
-const PLIC_CLAIM: usize = 0x0c20_0004;
+const PLIC_INT_ENABLE: usize = 0x0c00_2080;
+const PLIC_THRESHOLD: usize = 0x0c20_1000;
+const PLIC_CLAIM: usize = 0x0c20_1004;
 
-//PLIC是async cause 11
+//PLIC是async cause 9(S态外部中断)
 //声明claim会清除中断源上的相应pending位。
 //即使mip寄存器的MEIP位没有置位, 也可以claim; 声明不被阀值寄存器的设置影响；
 //获取按优先级排序后的下一个可用的中断ID
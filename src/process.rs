@@ -1,22 +1,46 @@
-use crate::cpu::{get_mtime, TrapFrame, mscratch_write, satp_write, satp_fence_asid, build_satp, SatpMode, KERNEL_TRAP_FRAME};
-use crate::page::{alloc, dealloc, map,unmap, zalloc, EntryBits, Table, PAGE_SIZE};
+use crate::cpu::{get_mtime, TrapFrame, mscratch_write, satp_write, satp_fence, satp_fence_asid, satp_asid, build_satp, SatpMode, KERNEL_TRAP_FRAME};
+use crate::page::{alloc, dealloc, map,unmap, zalloc, cow_fault, page_ref_inc, page_ref_dec, print_mappings, satp_mode, install_kernel, lookup, virt_to_phys, CowFault, Mapping, EntryBits, Table, PAGE_SIZE};
 use crate::user::init_process;
 use crate::fs::Inode;
+use crate::csr::Exception;
+use crate::kmem::KmemCache;
+use crate::perf::Counters;
 use crate::lock::Mutex;
 use alloc::collections::{vec_deque::VecDeque, BTreeMap};
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::ptr::null_mut;
 
 //每个进程的栈分配2个页
 const STACK_PAGES: usize = 2;
+//栈下面不映射的保护页, 栈溢出时会撞上
+const STACK_GUARD_PAGES: usize = 1;
 const STACK_ADDR: usize = 0x1_0000_0000;
-const PROCESS_STARTING_ADDR: usize = 0x2000_0000;
-//进程的开始执行地址, user mode
+//进程的程序镜像链接和映射在这, 不和内核的恒等映射(0x8000_0000以上的内存和低处的MMIO)重叠;
+//virt.lds里的_user_base要和它一致
+pub const PROCESS_STARTING_ADDR: usize = 0x4000_0000;
+
+extern "C" {
+	//user.rs里运行在U态的init进程所在的.text.user段: 链接地址和在内核镜像里的物理地址
+	static USER_START: usize;
+	static USER_END: usize;
+	static USER_LOAD: usize;
+}
 
 //进程列表，使用了global allocator
 pub static mut PROCESS_LIST: Option<VecDeque<Process>> = None;
 pub static mut PROCESS_LIST_MUTEX: Mutex = Mutex::new();
 static mut NEXT_PID: u16 = 1;
+//TrapFrame不再整页分配
+static mut TRAP_FRAME_CACHE: KmemCache = KmemCache::new("trap_frame", core::mem::size_of::<TrapFrame>(), 16, Some(trap_frame_ctor));
+
+fn trap_frame_ctor(obj: *mut u8) {
+	unsafe {
+		let frame = obj as *mut TrapFrame;
+		(*frame).qm = 1;
+		(*frame).trap_stack = KERNEL_TRAP_FRAME[0].trap_stack; //未来可取消？
+	}
+}
 
 pub fn set_running(pid: u16) -> bool {
 	let mut retval = false;
@@ -79,11 +103,19 @@ pub fn set_sleeping(pid: u16, duration: usize) -> bool {
 }
 
 pub fn add_process_default(pr: fn()) {
+	add_process(Process::new_default(pr));
+}
+
+//loader载入的程序, 见Process::new_image()
+pub fn add_process_image(image: *mut u8, len: usize) {
+	add_process(Process::new_image(image, len));
+}
+
+fn add_process(p: Process) {
 	unsafe {
 		//转移出来了Deque的所有权通过.take(), 转移后此时PROCESS_LIST是None
 		//这样允许互斥"mutual exclusion"
 		if let Some(mut pl) = PROCESS_LIST.take() {
-			let p = Process::new_default(pr);
 			pl.push_back(p);
 
 			//现在不再需要拥有Deque的所有权，还回去
@@ -150,16 +182,47 @@ pub struct Process {
 	pub sleep_until: usize,
 	pub program:	 *mut u8,
 	pub brk:         usize,
+	pub asid:        usize, //代号|ASID, 见asid.rs
+	pub perf:        Counters, //累计用掉的周期数/指令数等, 调度时记账, 见perf.rs
 }
 
 impl Process {
-	//参数是入口函数地址
+	//init进程: 参数是.text.user段里的入口函数, 地址已经是进程里的虚拟地址
 	pub fn new_default(func: fn()) -> Self {
 		let func_addr = func as usize;
-		let func_vaddr = func_addr;
+		let mut ret_proc = unsafe {
+			assert!(func_addr >= USER_START && func_addr < USER_END, "Entry 0x{:x} is not in .text.user", func_addr);
+			Process::new_empty(func_addr)
+		};
+		//只映射.text.user那一段, 不能把内核代码给U态; 只读, 多个进程共享
+		unsafe {
+			ret_proc.add_vma(USER_START, USER_END, EntryBits::UserReadExecute.val(), VmaBacking::Program(USER_LOAD));
+			println!("Process program:   0x{:x} ~ 0x{:x}", USER_START, USER_END);
+		}
+		ret_proc
+	}
+
+	//loader载入的程序: image是len字节的镜像(从页分配器分的), 程序链接在PROCESS_STARTING_ADDR, 入口在开头;
+	//镜像以写时复制方式映射, 进程退出时释放
+	pub fn new_image(image: *mut u8, len: usize) -> Self {
+		let mut ret_proc = Process::new_empty(PROCESS_STARTING_ADDR);
+		let end = PROCESS_STARTING_ADDR + (len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+		let bits = EntryBits::UserReadWriteExecute.val();
+		ret_proc.add_vma(PROCESS_STARTING_ADDR, end, bits, VmaBacking::Program(image as usize));
+		//镜像的页可能和其他进程共享(写时复制), 每页持有一个引用, 退出时和其他页一起释放
+		for page in (image as usize..image as usize + (end - PROCESS_STARTING_ADDR)).step_by(PAGE_SIZE) {
+			page_ref_inc(page);
+			ret_proc.data.pages.push_back(page);
+		}
+		println!("Process program:   0x{:x} ~ 0x{:x}", PROCESS_STARTING_ADDR, end);
+		ret_proc
+	}
+
+	//进程的公共部分: TrapFrame、页表、用户栈和内核的映射, 入口是entry; 程序镜像由调用者登记
+	fn new_empty(entry: usize) -> Self {
 		let mut ret_proc = 
-			Process { frame: zalloc(1) as *mut TrapFrame,
-			          stack: zalloc(STACK_PAGES),
+			Process { frame: unsafe { TRAP_FRAME_CACHE.alloc() } as *mut TrapFrame,
+			          stack: null_mut(), //栈页按需分配, 记录在data.pages
 				  pid:   unsafe { NEXT_PID },
 				  mmu_table:  zalloc(1) as *mut Table,
 				  state: ProcessState::Running,
@@ -167,62 +230,54 @@ impl Process {
 				  sleep_until: 0,
 				  program: null_mut(),
 				  brk: 0,
+				  asid: 0, //第一次被调度时才分配
+				  perf: Counters::zero(),
 			};
 
 		unsafe {
-			satp_fence_asid(NEXT_PID as usize);
 			//之后需要改进成原子加，防止调度导致的变量加法错误
 			NEXT_PID += 1;
 		}
 
-		let saddr = ret_proc.stack as usize;
 		unsafe {
-			(*ret_proc.frame).pc = func_vaddr;
-			(*ret_proc.frame).trap_stack = KERNEL_TRAP_FRAME[0].trap_stack; //未来可取消？
-
+			(*ret_proc.frame).pc = entry;
 			(*ret_proc.frame).pid = ret_proc.pid as usize;
-			//x2 = sp栈指针, 移动到申请到内存的底部
+			//x2 = sp栈指针, 移动到栈区域的底部
 			(*ret_proc.frame).regs[2] = STACK_ADDR + PAGE_SIZE * STACK_PAGES;
 
             //x1 = ra 返回地址寄存器设置？
 		}
 
-		let pt;
+		let pt = unsafe { &mut *ret_proc.mmu_table };
 		unsafe {
-			pt = &mut *ret_proc.mmu_table;
-			(*ret_proc.frame).satp = build_satp(SatpMode::Sv39, ret_proc.pid as usize, ret_proc.mmu_table as usize);
+			//ASID由调度器在切换时填进satp
+			(*ret_proc.frame).satp = build_satp(satp_mode(), 0, ret_proc.mmu_table as usize);
 			println!("Process {}, frame: {:#x}, mmu table: {:#x}", ret_proc.pid as usize, ret_proc.frame as usize, ret_proc.mmu_table as usize);
 		}
 
-		//把栈stack映射到用户空间的虚拟内存
-		for i in 0..STACK_PAGES {
-			let addr = i * PAGE_SIZE;
-			map(pt, STACK_ADDR + addr, saddr + addr, EntryBits::UserReadWrite.val(), 0);
-			println!("Map process stack:     0x{:x}", saddr + addr);
-		}
+		//这里只登记虚拟内存区域, 页在第一次访问时由handle_page_fault()分配和映射
 
-		let mut modifier = 0;
-		//映射PC等
-		//for i in 0..=100 {
-		for i in 0..=256 { // app < 1M
-			modifier = i * 0x1000;
-			map(pt, func_vaddr + modifier, func_addr + modifier, EntryBits::UserReadWriteExecute.val(), 0);
-		}
-		println!("Map process func_addr: 0x{:x} ~ 0x{:x}", func_addr, func_addr + modifier);
-
-		//make_syscall函数，现在在kernel里运行一个进程; 到时从块设备载入时，我们可载入指令到内存的任何处
-		//map(pt, 0x8000_0000, 0x8000_0000, EntryBits::UserReadExecute.val(), 0);
-        //
-        // 可能需要多映射几个页，保证PID:1 能够正常跑起来：
-		for i in 0..=20 {
-		modifier = i * 0x1000;
-		map(pt, 0x8000_0000 + modifier, 0x8000_0000 + modifier, EntryBits::UserReadExecute.val(), 0);
-		}
-		println!("Map init process:      0x80000000 ~ 0x{:x}", 0x80000000 + modifier);
+		//用户栈, 清零页; 下面是永远不映射的保护页
+		ret_proc.add_vma(STACK_ADDR, STACK_ADDR + PAGE_SIZE * STACK_PAGES, EntryBits::UserReadWrite.val(), VmaBacking::Stack);
+		ret_proc.add_vma(STACK_ADDR - PAGE_SIZE * STACK_GUARD_PAGES, STACK_ADDR, EntryBits::UserReadWrite.val(), VmaBacking::Guard);
+		println!("Process stack:     0x{:x} ~ 0x{:x}", STACK_ADDR, STACK_ADDR + PAGE_SIZE * STACK_PAGES);
+
+		//内核的一半: 全局、非用户的映射, 进程看不到也改不了
+		install_kernel(pt);
 
 		ret_proc
 	}
 
+	//登记一段虚拟内存区域, 先登记的优先匹配
+	pub fn add_vma(&mut self, start: usize, end: usize, bits: i64, backing: VmaBacking) {
+		assert!(start & (PAGE_SIZE - 1) == 0 && end & (PAGE_SIZE - 1) == 0 && start < end);
+		self.data.vmas.push(Vma { start, end, bits, backing });
+	}
+
+	pub fn find_vma(&self, vaddr: usize) -> Option<&Vma> {
+		self.data.vmas.iter().find(|v| v.contains(vaddr))
+	}
+
 }
 
 pub fn delete_process(pid: u16) {
@@ -231,6 +286,9 @@ pub fn delete_process(pid: u16) {
 			for i in 0..pl.len() {
 				let p = pl.get_mut(i).unwrap();
 				if (*(*p).frame).pid as u16 == pid {
+					let mut total = (*p).perf;
+					total.add(&crate::perf::pending(crate::cpu::hart_id(), pid));
+					println!("PID:{} exited, cycles: {}, instret: {}", pid, total.cycle, total.instret);
 					// When the structure gets dropped, all
 					// of the allocations get deallocated.
 					pl.remove(i);
@@ -245,6 +303,124 @@ pub fn delete_process(pid: u16) {
 	}
 }
 
+//store page fault: 解决写时复制; NotCow说明不是写时复制页, NoMemory时映射没动
+pub fn handle_store_fault(pid: u16, vaddr: usize) -> CowFault {
+	unsafe {
+		let p = get_by_pid(pid);
+		if p.is_null() {
+			return CowFault::NotCow;
+		}
+		let ret = cow_fault(&mut *(*p).mmu_table, vaddr);
+		if let CowFault::Copied(old, new) = ret {
+			//进程持有的页引用: 旧页的引用已释放, 换成新页
+			let pages = &mut (*p).data.pages;
+			if let Some(i) = pages.iter().position(|&x| x == old) {
+				pages.remove(i);
+			}
+			pages.push_back(new);
+		}
+		if ret.handled() {
+			satp_fence(vaddr, satp_asid((*(*p).frame).satp));
+		}
+		ret
+	}
+}
+
+//按需分页: 第一次访问某个区域里的页时分配并映射
+//cause是取指/读/写哪种页错误; 返回false说明访问不在任何区域内或权限不对, 是致命错误
+pub fn handle_page_fault(pid: u16, vaddr: usize, cause: Exception) -> bool {
+	unsafe {
+		let p = get_by_pid(pid);
+		if p.is_null() {
+			return false;
+		}
+		let (start, bits, backing) = match (*p).find_vma(vaddr) {
+			Some(vma) => (vma.start, vma.bits, vma.backing),
+			None => return false,
+		};
+		if let VmaBacking::Guard = backing {
+			println!("PID:{} stack overflow: hit guard page at 0x{:x}", pid, vaddr);
+			return false;
+		}
+		let need = match cause {
+			Exception::InstructionPageFault => EntryBits::Execute.val(),
+			Exception::LoadPageFault => EntryBits::Read.val(),
+			Exception::StorePageFault => EntryBits::Write.val(),
+			_ => return false,
+		};
+		if bits & need == 0 {
+			return false;
+		}
+
+		let pt = &mut *(*p).mmu_table;
+		let page = vaddr & !(PAGE_SIZE - 1);
+		if let Some(m) = lookup(pt, page) {
+			//已经有用户映射了, 只可能是写了写时复制页
+			//内核的全局映射不算, map()会把用户的页放进私有的页表里
+			if m.flags & EntryBits::User.val() != 0 {
+				return cause == Exception::StorePageFault && handle_store_fault(pid, vaddr).handled();
+			}
+		}
+		match backing {
+			VmaBacking::Anonymous | VmaBacking::Stack => {
+				let paddr = zalloc(1);
+				if paddr.is_null() {
+					return false;
+				}
+				map(pt, page, paddr as usize, bits, 0);
+				(*p).data.pages.push_back(paddr as usize);
+			},
+			VmaBacking::Program(base) => {
+				let paddr = base + (page - start);
+				let cow = if bits & EntryBits::Write.val() != 0 { EntryBits::Cow.val() } else { 0 };
+				map(pt, page, paddr, bits | cow, 0);
+			},
+			VmaBacking::Guard => unreachable!(),
+		}
+		satp_fence(page, satp_asid((*(*p).frame).satp));
+		true
+	}
+}
+
+//系统调用里访问用户地址: 翻译成物理地址, 没映射的话先按需分页
+//write为true时先解决写时复制, 免得内核直接写到共享的页上; 映射没有W(读时没有R)的返回None
+pub fn user_virt_to_phys(pid: u16, vaddr: usize, write: bool) -> Option<usize> {
+	unsafe {
+		let p = get_by_pid(pid);
+		if p.is_null() {
+			return None;
+		}
+		let is_user = |m: &Mapping| m.flags & EntryBits::User.val() != 0;
+		let cause = if write { Exception::StorePageFault } else { Exception::LoadPageFault };
+		if !lookup(&*(*p).mmu_table, vaddr).map_or(false, |m| is_user(&m)) && !handle_page_fault(pid, vaddr, cause) {
+			return None;
+		}
+		//分不到新页时还是只读的, 下面按没有W返回None
+		if write {
+			handle_store_fault(pid, vaddr);
+		}
+		//内核的映射不能让系统调用读写, 只读的页(比如和内核共享的.text.user)也不能写
+		let need = if write { EntryBits::Write.val() } else { EntryBits::Read.val() };
+		match lookup(&*(*p).mmu_table, vaddr) {
+			Some(m) if is_user(&m) && m.flags & need != 0 => virt_to_phys(&*(*p).mmu_table, vaddr),
+			_ => None,
+		}
+	}
+}
+
+//打印进程地址空间的所有映射, 缺页时用来排查
+pub fn dump_mappings(pid: u16) {
+	unsafe {
+		let p = get_by_pid(pid);
+		if p.is_null() {
+			println!("PID:{} not found", pid);
+			return;
+		}
+		println!("PID:{} address space:", pid);
+		print_mappings(&*(*p).mmu_table);
+	}
+}
+
 pub unsafe fn get_by_pid(pid: u16) -> *mut Process {
 	let mut ret = null_mut();
 	if let Some(mut pl) = PROCESS_LIST.take() {
@@ -276,7 +452,9 @@ pub enum Descriptor {
 //堆上的内存
 impl Drop for Process {
 	fn drop(&mut self) {
-		dealloc(self.stack);
+		if !self.stack.is_null() {
+			dealloc(self.stack);
+		}
 		unsafe {
 			unmap(&mut *self.mmu_table);
 		}
@@ -285,9 +463,14 @@ impl Drop for Process {
 
 		println!("Drop a process: {}", self.pid);
 
-		dealloc(self.frame as *mut u8);
+		unsafe {
+			//这块内存可能马上分给新进程, 不能还被当作某个hart上浮点寄存器的主人
+			crate::fpu::release(self.frame);
+			TRAP_FRAME_CACHE.free(self.frame as *mut u8);
+		}
+		//可能与其他进程共享, 只释放本进程的引用
 		for i in self.data.pages.drain(..) {
-			dealloc(i as *mut u8);
+			page_ref_dec(i);
 		}
 		// Kernel processes don't have a program, instead the program is linked
 		// directly in the kernel.
@@ -304,6 +487,7 @@ pub struct ProcessData {
 	pub fdesc: BTreeMap<u16, Descriptor>,
 	pub cwd: String,
 	pub pages: VecDeque<usize>,
+	pub vmas: Vec<Vma>,
 }
 
 impl ProcessData {
@@ -313,7 +497,35 @@ impl ProcessData {
 			fdesc: BTreeMap::new(),
 			cwd: String::from("/"),
 			pages: VecDeque::new(),
+			vmas: Vec::new(),
 		 }
 	}
 }
 
+//虚拟内存区域的页从哪来
+#[derive(Clone, Copy)]
+pub enum VmaBacking {
+	//清零页
+	Anonymous,
+	//程序镜像, 参数是区域开始处对应的物理地址(和虚拟地址无关); 可写的话按写时复制映射
+	Program(usize),
+	//用户栈, 也是清零页
+	Stack,
+	//栈下面的保护页, 不会被映射; 访问到就是栈溢出
+	Guard,
+}
+
+//进程的一段虚拟内存区域 [start, end)
+pub struct Vma {
+	pub start:   usize,
+	pub end:     usize,
+	pub bits:    i64,
+	pub backing: VmaBacking,
+}
+
+impl Vma {
+	pub fn contains(&self, vaddr: usize) -> bool {
+		vaddr >= self.start && vaddr < self.end
+	}
+}
+
@@ -0,0 +1,347 @@
+// sbi.rs
+// SBI客户端: 内核在S态通过ecall请M态的固件(OpenSBI, 或者-bios none时的machine.rs)做事
+//
+// 约定: a7 = 扩展号EID, a6 = 功能号FID, a0-a5 = 参数; 返回a0 = 错误码, a1 = 值
+// 固件不支持的扩展退回legacy(v0.1)调用, legacy只用a0返回; 要先init()探测固件有哪些扩展
+
+use crate::cpu::MAX_HARTS;
+
+//legacy扩展, 每个EID只有一个功能
+pub const EID_LEGACY_SET_TIMER: usize = 0x00;
+pub const EID_LEGACY_PUTCHAR: usize = 0x01;
+pub const EID_LEGACY_GETCHAR: usize = 0x02;
+pub const EID_LEGACY_SEND_IPI: usize = 0x04;
+pub const EID_LEGACY_REMOTE_FENCE_I: usize = 0x05;
+pub const EID_LEGACY_REMOTE_SFENCE_VMA: usize = 0x06;
+pub const EID_LEGACY_REMOTE_SFENCE_VMA_ASID: usize = 0x07;
+pub const EID_LEGACY_SHUTDOWN: usize = 0x08;
+
+pub const EID_BASE: usize = 0x10;
+pub const EID_TIME: usize = 0x5449_4d45;   //"TIME"
+pub const EID_IPI: usize = 0x73_5049;      //"sPI"
+pub const EID_RFENCE: usize = 0x5246_4e43; //"RFNC"
+pub const EID_HSM: usize = 0x48_534d;      //"HSM"
+pub const EID_SRST: usize = 0x5352_5354;   //"SRST"
+pub const EID_DBCN: usize = 0x4442_434e;   //"DBCN"
+//固件自定义扩展(0x0A000000 - 0x0AFFFFFF), 只有machine.rs实现
+pub const EID_SOS: usize = 0x0a53_4f53;    //"SOS"
+
+pub const SBI_SUCCESS: isize = 0;
+pub const SBI_ERR_FAILED: isize = -1;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+pub const SBI_ERR_DENIED: isize = -4;
+pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+
+//machine.rs报的实现号, 没有注册过
+pub const IMPL_ID_SOS: usize = 0x534f53; //"SOS"
+
+//HSM里hart的状态
+pub const HART_STARTED: usize = 0;
+pub const HART_STOPPED: usize = 1;
+pub const HART_START_PENDING: usize = 2;
+pub const HART_STOP_PENDING: usize = 3;
+
+//SRST的复位类型和原因
+pub const RESET_SHUTDOWN: usize = 0;
+pub const RESET_COLD_REBOOT: usize = 1;
+pub const RESET_WARM_REBOOT: usize = 2;
+pub const RESET_REASON_NONE: usize = 0;
+pub const RESET_REASON_FAILURE: usize = 1;
+
+//hart掩码的基址取这个值时表示所有hart
+const HART_MASK_ALL: usize = usize::MAX;
+
+//init()探测到的固件信息
+struct Firmware {
+	//init()之后才为true; 之前可能还在M态(-bios none的kinit), 不能ecall
+	ready: bool,
+	//(主版本, 次版本); legacy固件是(0, 1)
+	version: (usize, usize),
+	impl_id: usize,
+	impl_version: usize,
+	time: bool,
+	ipi: bool,
+	rfence: bool,
+	hsm: bool,
+	srst: bool,
+	dbcn: bool,
+}
+
+static mut FIRMWARE: Firmware = Firmware {
+	ready: false,
+	version: (0, 1),
+	impl_id: 0,
+	impl_version: 0,
+	time: false,
+	ipi: false,
+	rfence: false,
+	hsm: false,
+	srst: false,
+	dbcn: false,
+};
+
+fn ecall(eid: usize, fid: usize, a0: usize, a1: usize, a2: usize, a3: usize, a4: usize) -> (isize, usize) {
+	let err: isize;
+	let val: usize;
+	unsafe {
+		llvm_asm!("ecall"
+		     :"={x10}"(err), "={x11}"(val)
+		     :"{x10}"(a0), "{x11}"(a1), "{x12}"(a2), "{x13}"(a3), "{x14}"(a4), "{x16}"(fid), "{x17}"(eid)
+		     :"memory"
+		     :"volatile");
+	}
+	(err, val)
+}
+
+fn result((err, val): (isize, usize)) -> Result<usize, isize> {
+	if err == SBI_SUCCESS {
+		Ok(val)
+	}else{
+		Err(err)
+	}
+}
+
+//legacy调用只有一个返回值, 放在a0
+fn legacy(eid: usize, a0: usize, a1: usize, a2: usize, a3: usize) -> isize {
+	ecall(eid, 0, a0, a1, a2, a3, 0).0
+}
+
+//在S态调用一次, 之前的调用都按legacy固件处理
+pub fn init() {
+	unsafe { FIRMWARE.ready = true; }
+	//v0.1的固件不认识BASE扩展, 返回错误
+	let version = match result(ecall(EID_BASE, 0, 0, 0, 0, 0, 0)) {
+		Ok(v) => v,
+		Err(_) => {
+			println!("SBI: legacy firmware (v0.1)");
+			return;
+		}
+	};
+	unsafe {
+		FIRMWARE.version = ((version >> 24) & 0x7f, version & 0xff_ffff);
+		FIRMWARE.impl_id = result(ecall(EID_BASE, 1, 0, 0, 0, 0, 0)).unwrap_or(0);
+		FIRMWARE.impl_version = result(ecall(EID_BASE, 2, 0, 0, 0, 0, 0)).unwrap_or(0);
+		FIRMWARE.time = probe_extension(EID_TIME);
+		FIRMWARE.ipi = probe_extension(EID_IPI);
+		FIRMWARE.rfence = probe_extension(EID_RFENCE);
+		FIRMWARE.hsm = probe_extension(EID_HSM);
+		FIRMWARE.srst = probe_extension(EID_SRST);
+		FIRMWARE.dbcn = probe_extension(EID_DBCN);
+
+		let f = &FIRMWARE;
+		println!("SBI: v{}.{}, impl {} ({}) version {:#x}", f.version.0, f.version.1, impl_name(f.impl_id), f.impl_id, f.impl_version);
+		println!("SBI: TIME {} IPI {} RFENCE {} HSM {} SRST {} DBCN {}", f.time, f.ipi, f.rfence, f.hsm, f.srst, f.dbcn);
+	}
+}
+
+fn impl_name(id: usize) -> &'static str {
+	match id {
+		0 => "BBL",
+		1 => "OpenSBI",
+		2 => "Xvisor",
+		3 => "KVM",
+		4 => "RustSBI",
+		5 => "Diosix",
+		IMPL_ID_SOS => "SOS machine.rs",
+		_ => "unknown",
+	}
+}
+
+pub fn ready() -> bool {
+	unsafe { FIRMWARE.ready }
+}
+
+pub fn spec_version() -> (usize, usize) {
+	unsafe { FIRMWARE.version }
+}
+
+pub fn impl_id() -> usize {
+	unsafe { FIRMWARE.impl_id }
+}
+
+//固件是否实现了eid扩展; legacy固件返回false
+pub fn probe_extension(eid: usize) -> bool {
+	match result(ecall(EID_BASE, 3, eid, 0, 0, 0, 0)) {
+		Ok(v) => v != 0,
+		Err(_) => false,
+	}
+}
+
+//////////////////////////////////////
+// TIME / IPI / RFENCE
+// mask里第i位是hart i, 为0表示所有hart
+//////////////////////////////////////
+
+//stime时刻(mtime的值)给本hart一个S态时钟中断, 同时清掉现在挂着的
+pub fn set_timer(stime: usize) {
+	if unsafe { FIRMWARE.time } {
+		ecall(EID_TIME, 0, stime, 0, 0, 0, 0);
+	}else{
+		legacy(EID_LEGACY_SET_TIMER, stime, 0, 0, 0);
+	}
+}
+
+//mask为0时发给所有hart
+fn hart_mask(mask: usize) -> (usize, usize) {
+	if mask == 0 {
+		(0, HART_MASK_ALL)
+	}else{
+		(mask, 0)
+	}
+}
+
+//给mask里的hart发软件中断(S态收到SSIP)
+pub fn send_ipi(mask: usize) -> Result<(), isize> {
+	if unsafe { FIRMWARE.ipi } {
+		let (m, base) = hart_mask(mask);
+		return result(ecall(EID_IPI, 0, m, base, 0, 0, 0)).map(|_| ());
+	}
+	//legacy传的是掩码的地址
+	let m = legacy_mask(mask);
+	match legacy(EID_LEGACY_SEND_IPI, &m as *const usize as usize, 0, 0, 0) {
+		SBI_SUCCESS => Ok(()),
+		e => Err(e),
+	}
+}
+
+fn legacy_mask(mask: usize) -> usize {
+	if mask == 0 {
+		(1 << MAX_HARTS) - 1
+	}else{
+		mask
+	}
+}
+
+//让mask里的hart执行fence.i
+pub fn remote_fence_i(mask: usize) -> Result<(), isize> {
+	if unsafe { FIRMWARE.rfence } {
+		let (m, base) = hart_mask(mask);
+		return result(ecall(EID_RFENCE, 0, m, base, 0, 0, 0)).map(|_| ());
+	}
+	let m = legacy_mask(mask);
+	match legacy(EID_LEGACY_REMOTE_FENCE_I, &m as *const usize as usize, 0, 0, 0) {
+		SBI_SUCCESS => Ok(()),
+		e => Err(e),
+	}
+}
+
+//让mask里的hart刷新[start, start+size)的TLB; start和size都是0时刷新全部
+pub fn remote_sfence_vma(mask: usize, start: usize, size: usize) -> Result<(), isize> {
+	if unsafe { FIRMWARE.rfence } {
+		let (m, base) = hart_mask(mask);
+		return result(ecall(EID_RFENCE, 1, m, base, start, size, 0)).map(|_| ());
+	}
+	let m = legacy_mask(mask);
+	match legacy(EID_LEGACY_REMOTE_SFENCE_VMA, &m as *const usize as usize, start, size, 0) {
+		SBI_SUCCESS => Ok(()),
+		e => Err(e),
+	}
+}
+
+//同上, 只刷新asid的表项
+pub fn remote_sfence_vma_asid(mask: usize, start: usize, size: usize, asid: usize) -> Result<(), isize> {
+	if unsafe { FIRMWARE.rfence } {
+		let (m, base) = hart_mask(mask);
+		return result(ecall(EID_RFENCE, 2, m, base, start, size, asid)).map(|_| ());
+	}
+	let m = legacy_mask(mask);
+	match legacy(EID_LEGACY_REMOTE_SFENCE_VMA_ASID, &m as *const usize as usize, start, size, asid) {
+		SBI_SUCCESS => Ok(()),
+		e => Err(e),
+	}
+}
+
+//////////////////////////////////////
+// HSM: 启动/停止hart
+//////////////////////////////////////
+
+pub fn has_hsm() -> bool {
+	unsafe { FIRMWARE.hsm }
+}
+
+//让停着的hart从start开始在S态运行, 进去时a0 = hartid, a1 = opaque, 关MMU和中断
+pub fn hart_start(hartid: usize, start: usize, opaque: usize) -> Result<(), isize> {
+	if !has_hsm() {
+		return Err(SBI_ERR_NOT_SUPPORTED);
+	}
+	result(ecall(EID_HSM, 0, hartid, start, opaque, 0, 0)).map(|_| ())
+}
+
+//停掉本hart, 成功的话不会返回
+pub fn hart_stop() -> Result<(), isize> {
+	if !has_hsm() {
+		return Err(SBI_ERR_NOT_SUPPORTED);
+	}
+	result(ecall(EID_HSM, 1, 0, 0, 0, 0, 0)).map(|_| ())
+}
+
+//返回HART_STARTED等
+pub fn hart_status(hartid: usize) -> Result<usize, isize> {
+	if !has_hsm() {
+		return Err(SBI_ERR_NOT_SUPPORTED);
+	}
+	result(ecall(EID_HSM, 2, hartid, 0, 0, 0, 0))
+}
+
+//////////////////////////////////////
+// SRST: 关机/重启
+//////////////////////////////////////
+
+//成功不返回; 没有SRST时只能用legacy的shutdown
+pub fn system_reset(kind: usize, reason: usize) -> ! {
+	if unsafe { FIRMWARE.srst } {
+		ecall(EID_SRST, 0, kind, reason, 0, 0, 0);
+	}
+	if kind == RESET_SHUTDOWN {
+		legacy(EID_LEGACY_SHUTDOWN, 0, 0, 0, 0);
+	}
+	println!("SBI: system reset {} failed", kind);
+	loop {
+		unsafe {
+			llvm_asm!("wfi"::::"volatile");
+		}
+	}
+}
+
+pub fn shutdown() -> ! {
+	system_reset(RESET_SHUTDOWN, RESET_REASON_NONE)
+}
+
+//////////////////////////////////////
+// machine.rs的自定义扩展; 别的固件返回SBI_ERR_NOT_SUPPORTED
+//////////////////////////////////////
+
+//让本hart的mhpmcounter(3+i)计event事件并清零
+pub fn sos_set_hpm_event(i: usize, event: usize) -> Result<(), isize> {
+	result(ecall(EID_SOS, 0, i, event, 0, 0, 0)).map(|_| ())
+}
+
+//////////////////////////////////////
+// 控制台: 优先用DBCN, 没有就退回legacy的putchar/getchar
+//////////////////////////////////////
+
+pub fn console_putchar(c: u8) {
+	if unsafe { FIRMWARE.dbcn } {
+		ecall(EID_DBCN, 2, c as usize, 0, 0, 0, 0);
+	}else{
+		legacy(EID_LEGACY_PUTCHAR, c as usize, 0, 0, 0);
+	}
+}
+
+pub fn console_puts(s: &str) {
+	for c in s.bytes() {
+		console_putchar(c);
+	}
+}
+
+//没有输入时返回None; DBCN只有按缓冲区读的接口, 这里还是用legacy的
+pub fn console_getchar() -> Option<u8> {
+	let c = legacy(EID_LEGACY_GETCHAR, 0, 0, 0, 0);
+	if c < 0 {
+		None
+	}else{
+		Some(c as u8)
+	}
+}
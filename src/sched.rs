@@ -1,5 +1,26 @@
-use crate::process::{ProcessState, PROCESS_LIST, PROCESS_LIST_MUTEX};
-use crate::cpu::get_mtime;
+use crate::process::{Process, ProcessState, PROCESS_LIST, PROCESS_LIST_MUTEX};
+use crate::cpu::{build_satp, get_mtime, hart_id};
+use crate::page::satp_mode;
+use crate::{asid, perf};
+use alloc::collections::VecDeque;
+
+//切换前给进程分配(或确认)ASID, 写进它的satp
+unsafe fn prepare(prc: &mut Process) -> usize {
+	let asid = asid::activate(&mut prc.asid, hart_id());
+	(*prc.frame).satp = build_satp(satp_mode(), asid, prc.mmu_table as usize);
+	prc.frame as usize
+}
+
+//换上next(0表示没有进程可跑)之前, 把这段时间的计数记到刚才在本hart上跑的进程头上
+fn account(pl: &mut VecDeque<Process>, next: u16) {
+	let (prev, used) = perf::switch(hart_id(), next);
+	if prev == 0 {
+		return;
+	}
+	if let Some(p) = pl.iter_mut().find(|p| p.pid == prev) {
+		p.perf.add(&used);
+	}
+}
 
 pub fn schedule() -> usize {
 	let mut frame_addr: usize = 0x1111;
@@ -19,13 +40,13 @@ pub fn schedule() -> usize {
 				if let Some(prc) = pl.front_mut() {
 					match prc.state {
 						ProcessState::Running => {
-							frame_addr = prc.frame as usize;
+							frame_addr = prepare(prc);
 							break;
 						},
 						ProcessState::Sleeping => {
 							if prc.sleep_until <= get_mtime() {
 								prc.state = ProcessState::Running;
-								frame_addr = prc.frame as usize;
+								frame_addr = prepare(prc);
 								break;
 							}
 						},
@@ -37,6 +58,8 @@ pub fn schedule() -> usize {
 
             }//if
 
+			let next = if frame_addr == 0x1111 { 0 } else { pl.front().unwrap().pid };
+			account(&mut pl, next);
 			PROCESS_LIST.replace(pl);
 		}else{
 			println!("could not take process list");
@@ -1,7 +1,8 @@
-use crate::cpu::{dump_registers, Registers, TrapFrame, gp};
+use crate::cpu::{dump_registers, hart_id, Registers, TrapFrame, gp};
 use crate::page::{map, virt_to_phys, EntryBits, Table, PAGE_SIZE, zalloc};
-use crate::process::{add_kernel_process_args, delete_process, get_by_pid, set_sleeping, set_waiting, PROCESS_LIST, PROCESS_LIST_MUTEX, Descriptor};
+use crate::process::{add_kernel_process_args, delete_process, get_by_pid, set_sleeping, set_waiting, user_virt_to_phys, PROCESS_LIST, PROCESS_LIST_MUTEX, Descriptor};
 use crate::console::{IN_LOCK, IN_BUFFER, push_queue};
+use crate::perf::{self, Counters};
 
 use alloc::{boxed::Box, string::String};
 
@@ -91,8 +92,7 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 			let process = get_by_pid((*frame).pid as u16).as_ref().unwrap();
 			let mut iter = 0usize;
 			if (*frame).satp >> 60 != 0 {
-				let table = ((*process).mmu_table).as_mut().unwrap();
-				let paddr = virt_to_phys(table, buf as usize);
+				let paddr = user_virt_to_phys((*frame).pid as u16, buf as usize, true);
 				if let Some(bufaddr) = paddr {
 					buf = bufaddr as *mut u8;
 				}
@@ -147,8 +147,7 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 						for i in inb.drain(0..num_elements) {
 							//使能了MMU
 							if (*frame).satp >> 60 != 0 {
-								let table = ((*process).mmu_table).as_mut().unwrap();
-								let buf_addr = virt_to_phys(table, buf as usize);
+								let buf_addr = user_virt_to_phys((*frame).pid as u16, buf as usize, true);
 								if buf_addr.is_none() {
 									break;
 								}
@@ -183,7 +182,7 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 						let table = ((*process).mmu_table).as_mut().unwrap();
 						// We don't need to do the following until we reach a page boundary,
 						// however that code isn't written, yet.
-						let paddr = virt_to_phys(table, buf.add(i) as usize);
+						let paddr = user_virt_to_phys((*frame).pid as u16, buf.add(i) as usize, false);
 						if let Some(bufaddr) = paddr {
 							print!("{}", *(bufaddr as *const u8) as char);
 						}
@@ -239,6 +238,35 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 			// A0 = pid
 			(*frame).regs[Registers::A0 as usize] = (*frame).pid;
 		}
+		//SYSCALL_PERF_READ(自定义)
+		500 => {
+			// A0 = pid, 0表示自己; A1 = 用户缓冲区, 按perf::Counters的格式写
+			let me = (*frame).pid as u16;
+			let pid = match (*frame).regs[gp(Registers::A0)] {
+				0 => me,
+				p => p as u16,
+			};
+			let buf = (*frame).regs[gp(Registers::A1)];
+			let p = get_by_pid(pid);
+			if p.is_null() || buf % 8 != 0 {
+				(*frame).regs[gp(Registers::A0)] = -1isize as usize;
+				return;
+			}
+			let mut c = (*p).perf;
+			c.add(&perf::pending(hart_id(), pid));
+			//逐个字翻译, 缓冲区可能跨页
+			let src = &c as *const Counters as *const usize;
+			for i in 0..core::mem::size_of::<Counters>() / 8 {
+				match user_virt_to_phys(me, buf + i * 8, true) {
+					Some(paddr) => (paddr as *mut usize).write(*src.add(i)),
+					None => {
+						(*frame).regs[gp(Registers::A0)] = -1isize as usize;
+						return;
+					}
+				}
+			}
+			(*frame).regs[gp(Registers::A0)] = 0;
+		}
 
 		_ => {
 			println!("Unknown syscall number {}", syscall_number);
@@ -249,6 +277,7 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 }
 
 
+//内核在S态, 它发出的ecall会进M态(machine.rs), 下面这些只有在U态调用才会到do_syscall()
 extern "C" {
 	fn make_syscall(sysno: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize, arg5: usize) -> usize;
 }
@@ -1,30 +1,34 @@
 use crate::cpu::*;
+use crate::csr::{self, Cause, Exception, Interrupt, Mstatus, Sstatus};
+use crate::sbi;
+use crate::backtrace;
 use crate::{plic, uart};
 use crate::syscall::do_syscall;
 use crate::sched::schedule;
 use crate::rust_switch_to_user;
-use crate::page::{virt_to_phys, Table};
+use crate::page::{kernel_root, unmap_range, virt_to_phys, zalloc, CowFault, Table, PAGE_SIZE};
+use crate::process::{delete_process, dump_mappings, handle_page_fault, handle_store_fault};
 
 #[no_mangle]
-extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status: usize, frame: *mut TrapFrame) -> usize {
-	//在M态接管所有traps
-	let is_async = {
-		if cause >> 63 & 1 == 1 {
-			true
-		}else{
-			false
-		}
-	};
+extern "C" fn s_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status: usize, frame: *mut TrapFrame) -> usize {
+	//委派给S态的traps都在这; M态只剩时钟/IPI的转发, 见machine.rs
+	let cause = Cause(cause);
+	let is_async = cause.is_interrupt();
 
-	let cause_num = cause & 0xfff;
+	let cause_num = cause.code();
 	let mut return_pc = epc;
 	if is_async {
 		// Asynchronous trap 异步陷入
-		match cause_num {
-			  3 => {
-				  println!("Machine software interrupt CPU#{}", hart);
+		match cause.interrupt() {
+			  Some(Interrupt::SupervisorSoft) => {
+				  //IPI(M态转过来的)或者enter_scheduler()自己置位的: 调度一次
+				  csr::sip::clear(Interrupt::SupervisorSoft.mask());
+				  let new_frame = schedule();
+				  if new_frame != 0 && new_frame != 0x1111 {
+					  rust_switch_to_user(new_frame);
+				  }
 			  },
-			  7 => unsafe {
+			  Some(Interrupt::SupervisorTimer) => {
 				  // CLINT timer
 				  /*
 				  //设置下一次时钟中断的触发
@@ -35,7 +39,11 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
 				  */
 
 				  //time slicing时间切片来进行进程调度, 每秒调度另外一个进程
-                  //schedule_next_context_switch(1);
+				  //只有M态能清掉STIP, 设置下一次时钟时顺便清掉
+                  schedule_next_context_switch(1);
+
+				  #[cfg(feature = "kmem-harden")]
+				  heap_verify_tick();
 
 				  let new_frame = schedule();
 
@@ -48,10 +56,10 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
 					  rust_switch_to_user(new_frame);
 				  }
 			  },
-			  11 => {
+			  Some(Interrupt::SupervisorExternal) => {
 				  // PLIC
-                  // CPU的外部中断引脚连接到PLIC
-				  //println!("Machine external interrupt(PLIC) CPU#{}", hart);
+                  // CPU的外部中断引脚连接到PLIC, 用的是S态的context
+				  //println!("Supervisor external interrupt(PLIC) CPU#{}", hart);
 				  plic::handle_interrupt();
 			  },
 			  _ => {
@@ -60,33 +68,31 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
 		}
 	}else{
 		// Synchronous trap 同步陷入
-		match cause_num {
-			2 => {
+		match cause.exception() {
+			Some(Exception::IllegalInstruction) => {
 				// Illegal instruction
+				//进程第一次用浮点指令(FS还是Off), 打开FPU后重新执行这条指令
+				if crate::fpu::first_use(frame, hart, epc, tval, _status) {
+					return return_pc;
+				}
+				dump_trap(frame, epc, _status);
 				panic!("Illegal instruction CPU#{} -> 0x{:08x}: 0x{:08x}\n", hart, epc, tval);
 			},
-			3 => {
+			Some(Exception::Breakpoint) => {
 				// breakpoint
 				println!("\nBKPT");
-				println!("CPU#{}, mstatus: {:#x}, {:#x}: {:#x}", hart, _status, epc, tval);
+				println!("CPU#{}, sstatus: {:#x}, {:#x}: {:#x}", hart, _status, epc, tval);
 
-                let MPP = (_status & (0b11 << 11)) >> 11 ;
-                match MPP {
-                    0b11 => {
+                match Sstatus(_status).spp() {
+                    CpuMode::Machine => {
                         println!("RISC-V Machine Mode !");
                     },
-                    0b10 => {
-                        println!("RISC-V Hypervisor Mode !");
-                    },
-                    0b01 => {
+                    CpuMode::Supervisor => {
                         println!("RISC-V Supervisor Mode !");
                     },
-                    0b00 => {
+                    CpuMode::User => {
                         println!("RISC-V User Mode !");
                     },
-                    _ => {
-                        println!("Unknown RISC-V Privilege Mode !");
-                    }
                 }
 
 				return_pc += 2;
@@ -94,89 +100,89 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
 			/////////
 			// ecall指令触发的system call
 			//RISCV所有指令都是: 32位或16位压缩指令，ecall没有压缩形式故一直是32位
-			8 => {
+			Some(Exception::UserEcall) => {
 				// Environment (system) call from User mode
 				//println!("E-call from User mode! CPU#{} -> 0x{:08x}", hart, epc);
 				unsafe {
 					do_syscall(return_pc, frame);
 					//注意接下来的进程切换，pc需要正确
-					let frame = schedule();
-					//schedule_next_context_switch(1);
-
-                    if frame == 0 {
-                        println!("PROCESS_LIST locked !");
-                    }else if frame == 0x1111 {
-                        println!("PROCESS_LIST is empty !");
-
-                        mscratch_write((&mut KERNEL_TRAP_FRAME[0] as *mut TrapFrame) as usize);
-                        return_pc = KERNEL_TRAP_FRAME[0].pc;
-                        satp_write(KERNEL_TRAP_FRAME[0].satp);
-                        mstatus_write((0b11<<11) | (1<<7) | (1<<3));
-                        //还需要补上mie等,当初的kernel寄存器
-
-                        println!("mscratch: {:#x}, satp: {:#x}, mstatus: {:#x}", mscratch_read(), satp_read(), mstatus_read());
-
-                    }else{
-                        rust_switch_to_user(frame);
-                    }
-                }
-				//return_pc += 4;
-			},
-			9 => {
-				// Environment (system) call from Supervisor mode
-				println!("E-call from Supervisor mode! CPU#{} -> {:#x}", hart, epc);
-				unsafe {
-					do_syscall(return_pc, frame);
-					let frame = schedule();
-					//schedule_next_context_switch(1);
-
-					rust_switch_to_user(frame);
+					return_pc = switch_away(hart, return_pc);
 				}
+				//return_pc += 4;
 			},
-			11 => {
-				// Environment (system) call from Machine mode
-				println!("E-call from Machine mode! CPU#{} -> {:#x}", hart, epc);
-				unsafe {
-					do_syscall(return_pc, frame);
-					let frame = schedule();
-					//schedule_next_context_switch(1);
+			//S态的ecall由M态处理(machine.rs或SBI固件), M态的ecall不会委派过来
+			/////////
 
-					rust_switch_to_user(frame);
+			//硬件不做非对齐访问: 按字节模拟后跳过这条指令
+			Some(Exception::LoadMisaligned) | Some(Exception::StoreMisaligned) => {
+				let store = cause.exception() == Some(Exception::StoreMisaligned);
+				match crate::misaligned::emulate(frame, epc, _status, store) {
+					Some(pc) => return pc,
+					None => {
+						dump_trap(frame, epc, _status);
+						panic!("Misaligned access CPU#{} -> 0x{:08x}: 0x{:08x}\n", hart, epc, tval);
+					},
 				}
 			},
-			/////////
 
 
 			// Page faults
-			12 => {
+			Some(Exception::InstructionPageFault) => {
 				// Instruction page fault
 				unsafe {
+				if handle_page_fault((*frame).pid as u16, tval, Exception::InstructionPageFault) {
+					return epc;
+				}
+				report_guard_hit(tval);
 				println!("PID:{}, Instruction page fault CPU#{} -> 0x{:08x}: 0x{:08x}", (*frame).pid, hart, epc, tval);
+				dump_mappings((*frame).pid as u16);
 				}
-
+				dump_trap(frame, epc, _status);
 				loop {} //直到我们有个调度器删除的功能
 			},
-			13 => {
+			Some(Exception::LoadPageFault) => {
 				// Load page fault
 				unsafe {
-				println!("PID:{}, Load page fault CPU#{}, mstatus: {:#x} -> 0x{:08x}: 0x{:08x}", (*frame).pid, hart, _status, epc, tval);
+				if handle_page_fault((*frame).pid as u16, tval, Exception::LoadPageFault) {
+					return epc;
+				}
+				report_guard_hit(tval);
+				println!("PID:{}, Load page fault CPU#{}, sstatus: {:#x} -> 0x{:08x}: 0x{:08x}", (*frame).pid, hart, _status, epc, tval);
+				dump_mappings((*frame).pid as u16);
 				}
-				dump_registers(frame);
+				dump_trap(frame, epc, _status);
 				loop {} //直到我们有个调度器删除的功能
 			},
-			15 => {
+			Some(Exception::StorePageFault) => {
 				// Store page fault
 				unsafe {
-				let mt = (((*frame).satp << 12) & 0xffffffff) as *mut Table;
+				//写时复制的页复制或恢复可写, 或者按需分页, 然后重新执行该store指令
+				let pid = (*frame).pid as u16;
+				match handle_store_fault(pid, tval) {
+					CowFault::Reused | CowFault::Copied(..) => return epc,
+					//复制不了, 只能杀掉这个进程
+					CowFault::NoMemory => {
+						println!("PID:{} out of memory on copy-on-write at 0x{:x}, killed", pid, tval);
+						delete_process(pid);
+						return switch_away(hart, epc);
+					},
+					CowFault::NotCow => {},
+				}
+				if handle_page_fault(pid, tval, Exception::StorePageFault) {
+					return epc;
+				}
+				report_guard_hit(tval);
+				let mt = satp_root((*frame).satp) as *mut Table;
 				let mt = &mut *mt;
 				let paddr = virt_to_phys(mt, epc).unwrap(); 
-				println!("PID:{}, Store page fault CPU#{}, mstatus: {:#x} -> 0x{:08x}: 0x{:08x}, table:{:p}, paddr:0x{:x}", (*frame).pid, hart, _status, epc, tval, mt, paddr as usize);
+				println!("PID:{}, Store page fault CPU#{}, sstatus: {:#x} -> 0x{:08x}: 0x{:08x}, table:{:p}, paddr:0x{:x}", (*frame).pid, hart, _status, epc, tval, mt, paddr as usize);
+				dump_mappings((*frame).pid as u16);
 				}
-				dump_registers(frame);
+				dump_trap(frame, epc, _status);
 				loop {} //直到我们有个调度器删除的功能
 			},
 			_ => {
-				dump_registers(frame);
+				dump_trap(frame, epc, _status);
 				panic!("Unhandled sync trap CPU#{} -> {}\n", hart, cause_num);
 			}
 		}
@@ -186,12 +192,127 @@ extern "C" fn m_trap(epc: usize, tval: usize, cause: usize, hart: usize, _status
 	return_pc
 }
 
-pub const MMIO_MTIMECMP: *mut u64 = 0x0200_4000usize as *mut u64;
-pub const MMIO_MTIME: *const u64 = 0x0200_BFF8 as *const u64;
+//当前进程退出或者被杀掉之后, 换一个进程跑; 没有进程可跑时回到进trap之前的内核上下文
+unsafe fn switch_away(hart: usize, return_pc: usize) -> usize {
+	let frame = schedule();
+	//schedule_next_context_switch(1);
+
+	if frame == 0 {
+		println!("PROCESS_LIST locked !");
+		return_pc
+	}else if frame == 0x1111 {
+		println!("PROCESS_LIST is empty !");
+
+		//回到进trap之前的内核上下文, 比如kmain()里的enter_scheduler()
+		sscratch_write((&mut KERNEL_TRAP_FRAME[hart] as *mut TrapFrame) as usize);
+		satp_write(KERNEL_TRAP_FRAME[hart].satp);
+		csr::sstatus::write(Sstatus::new().with_spp(CpuMode::Supervisor).with_spie(true));
+
+		println!("sscratch: {:#x}, satp: {:#x}, sstatus: {:#x}", sscratch_read(), satp_read(), csr::sstatus::read_bits());
+		KERNEL_TRAP_FRAME[hart].pc
+	}else{
+		rust_switch_to_user(frame);
+	}
+}
+
+//致命的trap: 打印寄存器和进trap之前的调用栈
+fn dump_trap(frame: *mut TrapFrame, epc: usize, status: usize) {
+	dump_registers(frame);
+	backtrace::print_trap_backtrace(frame, epc, status);
+}
 
+//S态写不了mtimecmp, 让M态设置
 pub fn schedule_next_context_switch(qm: u16) {
+	sbi::set_timer(get_mtime().wrapping_add((CONTEXT_SWITCH_TIME * qm as u64) as usize));
+}
+
+//置位自己的SSIP, 在打开了中断的S态里马上会进s_trap保存当前上下文并开始调度;
+//没有进程可跑(或者进程都退出了)时从这里返回
+pub fn enter_scheduler() {
+	csr::sie::set(Interrupt::SupervisorSoft.mask());
+	csr::sip::set(Interrupt::SupervisorSoft.mask());
+	csr::sstatus::set(Mstatus::SIE);
+}
+
+//kmem-harden: 每隔这么多次时钟中断把内核堆整个检查一遍
+#[cfg(feature = "kmem-harden")]
+const HEAP_VERIFY_TICKS: usize = 16;
+#[cfg(feature = "kmem-harden")]
+static mut HEAP_VERIFY_COUNT: usize = 0;
+
+#[cfg(feature = "kmem-harden")]
+fn heap_verify_tick() {
 	unsafe {
-		MMIO_MTIMECMP.write_volatile(MMIO_MTIME.read_volatile().wrapping_add(CONTEXT_SWITCH_TIME * qm as u64));
+		HEAP_VERIFY_COUNT += 1;
+		if HEAP_VERIFY_COUNT % HEAP_VERIFY_TICKS != 0 {
+			return;
+		}
+	}
+	//有人正拿着堆的锁就下次再查
+	if let Some(bad) = crate::kmem::try_verify() {
+		if bad > 0 {
+			panic!("kernel heap corruption: {} problem(s) found", bad);
+		}
+	}
+}
+
+//每个hart的trap栈页数, 栈下面还有一个不映射的保护页
+pub const TRAP_STACK_PAGES: usize = 1;
+static mut TRAP_STACK_GUARDS: [usize; MAX_HARTS] = [0; MAX_HARTS];
+
+//hart 0建好内核页表后、任何hart写satp之前调用一次, 给所有hart分配trap栈;
+//每个栈最低的一页从内核页表里去掉当保护页, 这时还没有hart缓存内核页表, 不用通知别的hart刷TLB.
+//要在创建进程之前调用, 进程根页表复制的是当时的内核页表项
+pub fn alloc_trap_stacks() {
+	let root = kernel_root() as *mut Table;
+	assert!(!root.is_null());
+	for hart in 0..MAX_HARTS {
+		let mem = zalloc(TRAP_STACK_PAGES + 1);
+		assert!(!mem.is_null());
+		unsafe {
+			unmap_range(&mut *root, mem as usize, PAGE_SIZE, 0);
+			TRAP_STACK_GUARDS[hart] = mem as usize;
+		}
 	}
 }
 
+//hart的trap栈顶
+pub fn trap_stack(hart: usize) -> *mut u8 {
+	unsafe {
+		assert!(TRAP_STACK_GUARDS[hart] != 0);
+		(TRAP_STACK_GUARDS[hart] + (TRAP_STACK_PAGES + 1) * PAGE_SIZE) as *mut u8
+	}
+}
+
+//访问地址落在哪个hart的trap栈保护页上
+pub fn trap_stack_guard_hit(addr: usize) -> Option<usize> {
+	unsafe {
+		for hart in 0..MAX_HARTS {
+			let guard = TRAP_STACK_GUARDS[hart];
+			if guard != 0 && addr >= guard && addr < guard + PAGE_SIZE {
+				return Some(hart);
+			}
+		}
+	}
+	None
+}
+
+//地址是不是落在某个hart的trap栈上(不含保护页), 用来认出trap处理函数里又进了trap
+pub fn trap_stack_contains(addr: usize) -> bool {
+	unsafe {
+		for hart in 0..MAX_HARTS {
+			let bottom = TRAP_STACK_GUARDS[hart] + PAGE_SIZE;
+			if TRAP_STACK_GUARDS[hart] != 0 && addr >= bottom && addr < bottom + TRAP_STACK_PAGES * PAGE_SIZE {
+				return true;
+			}
+		}
+	}
+	false
+}
+
+//缺页无法处理时, 先看是不是内核trap栈溢出
+fn report_guard_hit(tval: usize) {
+	if let Some(h) = trap_stack_guard_hit(tval) {
+		println!("Kernel trap stack overflow on CPU#{}: hit guard page at 0x{:x}", h, tval);
+	}
+}
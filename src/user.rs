@@ -3,9 +3,17 @@ const SYSCALL_WRITE: usize = 64;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_GET_TIME: usize = 169;
+const SYSCALL_PERF_READ: usize = 500;
 
 pub const STDOUT: usize = 1;
 
+//init进程链接在内核里却运行在U态: 它用到的代码和数据都放进.text.user/.rodata.user,
+//进程只能看到这一段(见virt.lds), 看不到内核的其他部分
+const INIT_MSG_LEN: usize = 18;
+#[link_section = ".rodata.user"]
+static INIT_MSG: [u8; INIT_MSG_LEN] = *b"\ninit from U mode\n";
+
+#[link_section = ".text.user"]
 pub fn syscall(id: usize, arg0: usize, arg1: usize, arg2: usize) -> isize {
 	let mut ret: isize;
 	unsafe{
@@ -18,25 +26,37 @@ pub fn syscall(id: usize, arg0: usize, arg1: usize, arg2: usize) -> isize {
 	ret
 }
 
+#[link_section = ".text.user"]
 pub fn sys_yield() -> isize {
 	syscall(SYSCALL_YIELD, 0, 0, 0)
 }
 
-pub fn sys_write(fd: usize, buffer: &[u8]) -> isize {
-	syscall(SYSCALL_WRITE, fd, buffer.as_ptr() as usize, buffer.len())
+//指针和长度直接传usize: 切片的as_ptr()/len()在debug构建里不内联, 会跳到内核的.text
+#[link_section = ".text.user"]
+pub fn sys_write(fd: usize, buf: usize, len: usize) -> isize {
+	syscall(SYSCALL_WRITE, fd, buf, len)
 }
 
+#[link_section = ".text.user"]
 pub fn sys_exit(state: i32) -> isize {
 	syscall(SYSCALL_EXIT, state as usize, 0, 0)
 }
 
+//读pid(0表示自己)累计的周期数/指令数等; cycle/instret也可以直接用rdcycle/rdinstret读本hart的
+#[link_section = ".text.user"]
+pub fn sys_perf_read(pid: usize, counters: &mut crate::perf::Counters) -> isize {
+	syscall(SYSCALL_PERF_READ, pid, counters as *mut _ as usize, 0)
+}
+
+#[link_section = ".text.user"]
 pub fn init_process() {
 	let mut i: usize = 0;
-	sys_write(STDOUT, "\ninit from U mode\n".as_bytes());
+	sys_write(STDOUT, &INIT_MSG as *const _ as usize, INIT_MSG_LEN);
 
 	//运行在U态
     loop {
-        i += 1;
+        //debug构建里的+=带溢出检查, panic路径在内核的.text里
+        i = i.wrapping_add(1);
         if i > 70_000_000 {
             unsafe {
                 syscall(1, 0, 0, 0);